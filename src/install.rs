@@ -8,6 +8,118 @@ const SERVICE_NAME: &str = "desktop-service";
 #[cfg(not(windows))]
 use anyhow::Error;
 
+/// 生成 API token，属于 root，仅 `--group` 指定的用户组可读
+/// 已存在时保留原有的 token，只重新设置权限
+#[cfg(any(windows, target_os = "linux", target_os = "macos"))]
+fn write_token() {
+    use rand::Rng;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    #[cfg(target_os = "linux")]
+    let dir = PathBuf::from("/etc/desktop-service");
+    #[cfg(target_os = "macos")]
+    let dir = PathBuf::from("/Library/Application Support/desktop-service");
+    #[cfg(windows)]
+    let dir =
+        PathBuf::from(std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".into()))
+            .join(SERVICE_NAME);
+
+    std::fs::create_dir_all(&dir).expect("Unable to create config directory");
+    let token_file = dir.join("token");
+
+    let args: Vec<String> = std::env::args().collect();
+    let group = args
+        .iter()
+        .position(|arg| arg == "--group")
+        .and_then(|i| args.get(i + 1).cloned());
+
+    // 默认允许运行安装程序的用户读取，与 Unix 上使用 SUDO_GID 一致；
+    // 以 SYSTEM 等服务账户运行时没有对应的用户，必须指定 `--group`
+    #[cfg(windows)]
+    let group =
+        group.unwrap_or_else(
+            || match (std::env::var("USERDOMAIN"), std::env::var("USERNAME")) {
+                (Ok(domain), Ok(user)) if !user.ends_with('$') => format!("{domain}\\{user}"),
+                _ => {
+                    eprintln!("--group is required when the installer is not run by a user.");
+                    std::process::exit(2);
+                }
+            },
+        );
+
+    // 先创建空文件并设置好权限，再写入 token，避免被其他用户读到
+    let created = if token_file.exists() {
+        None
+    } else {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(not(windows))]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        Some(
+            options
+                .open(&token_file)
+                .expect("Unable to create token file"),
+        )
+    };
+
+    #[cfg(not(windows))]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // 默认使用调用 sudo 的用户的组
+        let group = group
+            .or_else(|| std::env::var("SUDO_GID").ok())
+            .unwrap_or_else(|| "0".into());
+        std::process::Command::new("chown")
+            .arg(format!("0:{group}"))
+            .arg(&token_file)
+            .output()
+            .expect("Failed to chown");
+        std::fs::set_permissions(&token_file, std::fs::Permissions::from_mode(0o640))
+            .expect("Failed to chmod");
+    }
+
+    #[cfg(windows)]
+    {
+        let output = std::process::Command::new("icacls")
+            .arg(&token_file)
+            .args([
+                "/inheritance:r",
+                "/grant:r",
+                "*S-1-5-18:F",
+                "/grant:r",
+                "*S-1-5-32-544:F",
+            ])
+            .arg("/grant:r")
+            .arg(format!("{group}:R"))
+            .output()
+            .expect("Failed to set token file permissions");
+        if !output.status.success() {
+            // 不留下空的 token 文件，否则下次安装会沿用它
+            if let Some(file) = created {
+                drop(file);
+                let _ = std::fs::remove_file(&token_file);
+            }
+            eprintln!(
+                "Failed to grant `{group}` read access to the token: {}",
+                String::from_utf8_lossy(&output.stdout).trim()
+            );
+            std::process::exit(2);
+        }
+    }
+
+    if let Some(mut file) = created {
+        let token: String = rand::thread_rng()
+            .gen::<[u8; 32]>()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        file.write_all(token.as_bytes())
+            .expect("Unable to write token file");
+    }
+}
+
 #[cfg(target_os = "macos")]
 fn main() -> Result<(), Error> {
     use regex::Regex;
@@ -26,6 +138,8 @@ fn main() -> Result<(), Error> {
         std::process::exit(2);
     }
 
+    write_token();
+
     let dot_name = Regex::new(r"[\-_]").unwrap().replace_all(SERVICE_NAME, ".");
 
     let target_binary_path = format!("/Library/PrivilegedHelperTools/{}.helper", dot_name);
@@ -106,6 +220,27 @@ fn main() -> Result<(), Error> {
         std::process::exit(2);
     }
 
+    write_token();
+
+    let unit_file = format!("/etc/systemd/system/{}.service", SERVICE_NAME);
+    let unit_file = Path::new(&unit_file);
+
+    let unit_file_content = format!(
+        include_str!("files/systemd.tmpl"),
+        service_binary_path.to_str().unwrap()
+    );
+
+    // Upgrade the unit file written by an older version, e.g. without `Delegate=yes`.
+    let outdated =
+        std::fs::read_to_string(unit_file).is_ok_and(|content| content != unit_file_content);
+    if outdated {
+        std::fs::write(unit_file, &unit_file_content).expect("Unable to write unit file");
+        std::process::Command::new("systemctl")
+            .arg("daemon-reload")
+            .output()
+            .expect("Failed to execute 'systemctl daemon-reload' command.");
+    }
+
     // Peek the status of the service.
     let status_code = std::process::Command::new("systemctl")
         .arg("status")
@@ -121,7 +256,16 @@ fn main() -> Result<(), Error> {
      */
     match status_code {
         Some(code) => match code {
-            0 => return Ok(()),
+            0 => {
+                if outdated {
+                    std::process::Command::new("systemctl")
+                        .arg("restart")
+                        .arg(format!("{}.service", SERVICE_NAME))
+                        .output()
+                        .expect("Failed to execute 'systemctl restart' command.");
+                }
+                return Ok(());
+            }
             1 | 2 | 3 => {
                 std::process::Command::new("systemctl")
                     .arg("start")
@@ -140,13 +284,6 @@ fn main() -> Result<(), Error> {
         }
     }
 
-    let unit_file = format!("/etc/systemd/system/{}.service", SERVICE_NAME);
-    let unit_file = Path::new(&unit_file);
-
-    let unit_file_content = format!(
-        include_str!("files/systemd.tmpl"),
-        service_binary_path.to_str().unwrap()
-    );
     let mut file = File::create(unit_file).expect("Failed to create file for writing.");
     file.write_all(unit_file_content.as_bytes())
         .expect("Unable to write unit file");
@@ -178,6 +315,8 @@ fn main() -> windows_service::Result<()> {
         service_manager::{ServiceManager, ServiceManagerAccess},
     };
 
+    write_token();
+
     let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
     let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
 
@@ -0,0 +1,189 @@
+use super::config::Config;
+use super::data::*;
+use std::path::Path;
+use std::sync::Arc;
+use warp::body::BodyDeserializeError;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// 修改状态的请求必须携带的自定义头
+/// 浏览器跨域发送自定义头前需要预检，而服务不响应预检
+pub const CSRF_HEADER: &str = "x-desktop-service";
+
+/// API token，Debug 时不输出内容
+#[derive(Clone)]
+pub struct Token(Arc<str>);
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+/// 读取 token，文件不存在或为空时返回 None，此时拒绝所有需要 token 的请求
+pub fn load_token(path: &Path) -> Option<Token> {
+    match std::fs::read_to_string(path) {
+        Ok(token) if !token.trim().is_empty() => Some(Token(token.trim().into())),
+        Ok(_) => {
+            log::warn!("token file `{}` is empty", path.display());
+            None
+        }
+        Err(err) => {
+            log::warn!("failed to read token file `{}`: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// 已通过其他方式认证的请求，如 Unix socket 的对端凭据，不再检查 token
+#[derive(Debug, Clone, Copy)]
+pub struct PeerVerified;
+
+#[derive(Debug)]
+struct Unauthorized(&'static str);
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+struct Forbidden(&'static str);
+
+impl warp::reject::Reject for Forbidden {}
+
+/// 要求请求携带 `Authorization: Bearer <token>`，token 随配置重新加载
+pub fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<PeerVerified>()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |verified: Option<PeerVerified>, header: Option<String>| {
+                let token = Config::current().token.clone();
+                async move {
+                    if verified.is_some() {
+                        return Ok(());
+                    }
+                    let Some(token) = token else {
+                        return Err(warp::reject::custom(Unauthorized(
+                            "token is not configured",
+                        )));
+                    };
+                    let provided = header
+                        .as_deref()
+                        .and_then(|h| h.strip_prefix("Bearer "))
+                        .map(str::trim);
+                    match provided {
+                        Some(provided)
+                            if constant_time_eq(provided.as_bytes(), token.0.as_bytes()) =>
+                        {
+                            Ok(())
+                        }
+                        Some(_) => Err(warp::reject::custom(Unauthorized("invalid token"))),
+                        None => Err(warp::reject::custom(Unauthorized("missing bearer token"))),
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// 拒绝来自浏览器的请求，防御 DNS rebinding 和跨站请求
+/// 通过 Unix socket 的请求不会来自浏览器，不检查 Host
+pub fn local_client() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<PeerVerified>()
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("origin"))
+        .and_then(
+            |verified: Option<PeerVerified>, host: Option<String>, origin: Option<String>| async move {
+                if origin.is_some() {
+                    return Err(warp::reject::custom(Forbidden(
+                        "requests from browsers are not allowed",
+                    )));
+                }
+                if verified.is_none() && !host.as_deref().is_some_and(is_loopback_host) {
+                    return Err(warp::reject::custom(Forbidden("invalid host header")));
+                }
+                Ok(())
+            },
+        )
+        .untuple_one()
+}
+
+/// 用于修改状态的路由，要求携带 `CSRF_HEADER`
+pub fn csrf_header() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>(CSRF_HEADER)
+        .and_then(|value: Option<String>| async move {
+            match value {
+                Some(_) => Ok(()),
+                None => Err(warp::reject::custom(Forbidden(
+                    "missing X-Desktop-Service header",
+                ))),
+            }
+        })
+        .untuple_one()
+}
+
+/// 忽略端口，只接受回环地址和 localhost
+fn is_loopback_host(host: &str) -> bool {
+    let hostname = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    hostname.eq_ignore_ascii_case("localhost") || hostname == "127.0.0.1" || hostname == "::1"
+}
+
+/// 把认证失败和请求体格式错误转换为 JSON 响应，其余的 rejection 保持 warp 默认的处理
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    let (code, status, msg) = if let Some(Unauthorized(msg)) = rejection.find() {
+        (CODE_UNAUTHORIZED, StatusCode::UNAUTHORIZED, msg.to_string())
+    } else if let Some(Forbidden(msg)) = rejection.find() {
+        (CODE_FORBIDDEN, StatusCode::FORBIDDEN, msg.to_string())
+    } else if let Some(err) = rejection.find::<BodyDeserializeError>() {
+        // 请求体格式错误时同样返回 JSON，包含出错的字段
+        (400, StatusCode::BAD_REQUEST, err.to_string())
+    } else {
+        return Err(rejection);
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&JsonResponse {
+            code,
+            msg,
+            data: Option::<()>::None,
+        }),
+        status,
+    ))
+}
+
+/// 比较耗时与内容无关，避免通过响应时间逐字节猜测 token
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_hosts() {
+        for host in [
+            "localhost",
+            "LocalHost:27247",
+            "127.0.0.1",
+            "127.0.0.1:27247",
+            "[::1]",
+            "[::1]:27247",
+        ] {
+            assert!(is_loopback_host(host), "{host}");
+        }
+        for host in [
+            "",
+            "example.com",
+            "localhost.example.com:27247",
+            "127.0.0.2:27247",
+            "0.0.0.0:27247",
+            "[::2]:27247",
+        ] {
+            assert!(!is_loopback_host(host), "{host}");
+        }
+    }
+}
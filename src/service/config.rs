@@ -0,0 +1,306 @@
+use super::auth::{load_token, Token};
+use super::data::*;
+#[cfg(unix)]
+use super::policy::check_owner;
+use super::policy::Policy;
+use super::{config_dir, log_dir};
+use anyhow::{anyhow, bail, Result};
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// 服务配置，位于配置目录中
+const CONFIG_FILE: &str = "config.toml";
+/// 当前支持的配置版本
+pub const CONFIG_VERSION: u32 = 1;
+
+/// 包含策略在内的所有配置，必须属于 root 且只有 root 可写
+///
+/// ```toml
+/// version = 1
+///
+/// [tcp]
+/// enabled = true
+/// address = "127.0.0.1"
+/// port = 27247
+///
+/// [auth]
+/// token_file = "/etc/desktop-service/token"
+///
+/// [paths]
+/// log_dirs = ["/var/log/desktop-service"]
+/// bin_dirs = ["/opt/app"]
+///
+/// # 请求未指定 restart 时使用
+/// [restart]
+/// mode = "on-failure"
+///
+/// [[policy.allow]]
+/// path = "/opt/app/core"
+/// sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+///
+/// [unix_socket]
+/// path = "/run/desktop-service.sock"
+/// mode = "660"
+/// group = "desktop-service"
+/// allow_users = ["alice"]
+///
+/// # 仅 Linux
+/// [dns]
+/// backend = "auto"
+/// resolv_conf = "/etc/resolv.conf"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub version: u32,
+    pub tcp: TcpConfig,
+    pub auth: AuthConfig,
+    pub paths: PathsConfig,
+    pub restart: RestartPolicy,
+    pub policy: Policy,
+    #[cfg(unix)]
+    pub unix_socket: Option<UnixSocketConfig>,
+    #[cfg(target_os = "linux")]
+    pub dns: DnsConfig,
+    /// 从 `auth.token_file` 读取，随配置一起重新加载
+    #[serde(skip)]
+    pub token: Option<Token>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            tcp: TcpConfig::default(),
+            auth: AuthConfig::default(),
+            paths: PathsConfig::default(),
+            restart: RestartPolicy::default(),
+            policy: Policy::default(),
+            #[cfg(unix)]
+            unix_socket: None,
+            #[cfg(target_os = "linux")]
+            dns: DnsConfig::default(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// 关闭后只通过 Unix socket 提供服务
+    pub enabled: bool,
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 27247,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// 由安装程序生成，属于 root，仅配置的用户组可读
+    pub token_file: PathBuf,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            token_file: config_dir().join("token"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsConfig {
+    /// `log_file` 和 `stderr_file` 必须位于这些目录中
+    pub log_dirs: Vec<PathBuf>,
+    /// `bin_path` 必须位于这些目录中，为空时只按策略文件检查
+    pub bin_dirs: Vec<PathBuf>,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        Self {
+            log_dirs: vec![log_dir()],
+            bin_dirs: Vec::new(),
+        }
+    }
+}
+
+#[cfg(unix)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocketConfig {
+    #[serde(default = "default_socket_path")]
+    pub path: PathBuf,
+    /// 八进制的文件权限
+    #[serde(default = "default_socket_mode")]
+    pub mode: String,
+    /// socket 文件所属的组，组内用户可以调用 API
+    pub group: Option<String>,
+    /// 组外额外允许的用户，root 总是允许
+    #[serde(default)]
+    pub allow_users: Vec<String>,
+}
+
+#[cfg(unix)]
+fn default_socket_path() -> PathBuf {
+    PathBuf::from("/var/run/desktop-service.sock")
+}
+
+#[cfg(unix)]
+fn default_socket_mode() -> String {
+    "660".into()
+}
+
+/// 修改 DNS 的方式
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsBackendKind {
+    /// 检测系统正在使用的方式
+    #[default]
+    Auto,
+    SystemdResolved,
+    NetworkManager,
+    ResolvConf,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    pub backend: DnsBackendKind,
+    /// 直接修改或用于检测的 resolv.conf
+    pub resolv_conf: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            backend: DnsBackendKind::Auto,
+            resolv_conf: PathBuf::from("/etc/resolv.conf"),
+        }
+    }
+}
+
+impl Config {
+    pub fn global() -> &'static RwLock<Arc<Config>> {
+        static CONFIG: OnceCell<RwLock<Arc<Config>>> = OnceCell::new();
+
+        CONFIG.get_or_init(|| RwLock::new(Arc::new(Config::default())))
+    }
+
+    /// 当前生效的配置
+    pub fn current() -> Arc<Config> {
+        Self::global().read().clone()
+    }
+
+    /// 配置文件不存在时使用默认值
+    pub fn load() -> Result<Self> {
+        let path = config_dir().join(CONFIG_FILE);
+        let mut config: Self = match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                #[cfg(unix)]
+                check_owner(&path)?;
+                toml::from_str(&content)
+                    .map_err(|err| anyhow!("invalid config `{}`: {err}", path.display()))?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => bail!("failed to read `{}`: {err}", path.display()),
+        };
+        config.validate()?;
+        config.token = load_token(&config.auth.token_file);
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.version != CONFIG_VERSION {
+            bail!(
+                "unsupported config version {}, expected {CONFIG_VERSION}",
+                self.version
+            );
+        }
+        if !(0.0..=1.0).contains(&self.restart.jitter) {
+            bail!("restart.jitter must be between 0 and 1");
+        }
+        self.policy.validate()?;
+        #[cfg(unix)]
+        if let Some(socket) = &self.unix_socket {
+            socket.mode()?;
+        }
+        Ok(())
+    }
+
+    /// 重新读取配置，校验失败时保留当前的配置，已运行的进程不受影响
+    /// 监听地址的修改需要重启服务才能生效
+    pub fn reload() -> Result<ReloadInfo> {
+        let mut config = Self::load()?;
+        let mut current = Self::global().write();
+
+        // 监听配置保持为实际使用中的值，直到重启
+        let restart_required = config.tcp != current.tcp;
+        config.tcp = current.tcp.clone();
+        #[cfg(unix)]
+        let restart_required = restart_required || config.unix_socket != current.unix_socket;
+        #[cfg(unix)]
+        {
+            config.unix_socket = current.unix_socket.clone();
+        }
+
+        let version = config.version;
+        *current = Arc::new(config);
+        Ok(ReloadInfo {
+            version,
+            restart_required,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl UnixSocketConfig {
+    pub fn mode(&self) -> Result<u32> {
+        match u32::from_str_radix(&self.mode, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(mode),
+            _ => bail!("invalid socket mode `{}`", self.mode),
+        }
+    }
+}
+
+/// 收到 SIGHUP 时重新加载配置
+#[cfg(unix)]
+pub fn reload_on_sighup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async {
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            log::warn!("failed to listen for SIGHUP");
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            match Config::reload() {
+                Ok(info) if info.restart_required => {
+                    log::warn!("config reloaded, listener changes require a restart")
+                }
+                Ok(_) => log::info!("config reloaded"),
+                Err(err) => log::error!("failed to reload config: {err:#}"),
+            }
+        }
+    });
+}
@@ -1,15 +1,286 @@
+use super::config::Config;
+use super::dns::SavedDns;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// 未指定实例名时使用的默认名称
+pub const DEFAULT_NAME: &str = "core";
+
+fn default_name() -> String {
+    DEFAULT_NAME.into()
+}
+
+/// 未指定 restart 时使用配置中的默认值
+fn default_restart() -> RestartPolicy {
+    Config::current().restart.clone()
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StartBody {
+    #[serde(default = "default_name")]
+    pub name: String,
     pub bin_path: String,
     pub args: Vec<String>,
     pub log_file: String,
+    #[serde(default)]
+    pub stdio: StdioMode,
+    /// `separate` 模式下 stderr 的输出文件
+    pub stderr_file: Option<String>,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    #[serde(default = "default_restart")]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub env: EnvConfig,
+    /// 工作目录，默认继承服务进程
+    pub cwd: Option<String>,
+    /// 八进制字符串，如 `"022"`
+    pub umask: Option<String>,
+    /// 以指定用户运行，用户名或 uid
+    pub user: Option<String>,
+    /// 以指定用户组运行，默认为用户的主组
+    pub group: Option<String>,
+    /// 切换用户后保留的 capabilities，如 `CAP_NET_ADMIN`，仅支持 Linux
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub limits: ResourceLimits,
+}
+
+/// 资源限制，未设置的项不做限制
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// 最大打开文件数（RLIMIT_NOFILE）
+    pub open_files: Option<u64>,
+    /// 虚拟内存上限，字节（RLIMIT_AS）
+    pub memory: Option<u64>,
+    /// CPU 时间上限，秒（RLIMIT_CPU）
+    pub cpu_time: Option<u64>,
+    /// core dump 大小上限，字节，0 表示禁止（RLIMIT_CORE）
+    pub core_dump: Option<u64>,
+    /// cgroup v2 的 memory.max，字节，仅支持 Linux
+    pub memory_max: Option<u64>,
+    /// cgroup v2 的 cpu.max，以 CPU 核数计，如 0.5，仅支持 Linux
+    pub cpu_max: Option<f64>,
+}
+
+/// 导致进程退出的资源限制
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitHit {
+    /// 超出 memory_max 被 OOM killer 结束
+    Memory,
+    /// 超出 cpu_time 收到 SIGXCPU
+    CpuTime,
+    /// 超出文件大小限制收到 SIGXFSZ
+    FileSize,
+}
+
+/// 子进程的环境变量
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct EnvConfig {
+    /// 不继承服务进程的环境变量
+    pub clear: bool,
+    pub set: HashMap<String, String>,
+    pub unset: Vec<String>,
+}
+
+/// 日志轮转策略
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LogRotation {
+    /// 单个日志文件的最大字节数，0 表示不按大小轮转
+    /// 单次读取的输出不会被拆分，文件可能略微超过该值
+    pub max_size: u64,
+    /// 单个日志文件的最长写入时间（秒），0 表示不按时间轮转
+    pub max_age_secs: u64,
+    /// 保留的归档数量
+    pub keep: u32,
+    /// 是否压缩归档
+    pub gzip: bool,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            max_age_secs: 0,
+            keep: 5,
+            gzip: false,
+        }
+    }
+}
+
+/// 子进程 stdout / stderr 的去向
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StdioMode {
+    /// stdout 和 stderr 都写入 `log_file`
+    #[default]
+    Merged,
+    /// stdout 写入 `log_file`，stderr 写入 `stderr_file`
+    Separate,
+    /// 丢弃所有输出
+    Discard,
+}
+
+/// 进程退出后的重启方式
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+/// 重启策略，语义参考 systemd 的 `Restart=`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    /// 连续重启的最大次数，0 表示不限制
+    pub max_retries: u32,
+    /// 首次重启前的等待时间，之后每次翻倍
+    pub backoff_ms: u64,
+    /// 等待时间的上限
+    pub max_backoff_ms: u64,
+    /// 随机抖动比例，取值 0 ~ 1
+    pub jitter: f64,
+    /// 进程持续运行超过该时间后重置重启计数
+    pub reset_after_secs: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RestartMode::Never,
+            max_retries: 5,
+            backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            jitter: 0.2,
+            reset_after_secs: 60,
+        }
+    }
+}
+
+/// server 进程的运行状态
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    /// 等待启动或重启
+    Starting,
+    Running,
+    /// 已发送停止信号，等待退出
+    Stopping,
+    /// 正常退出
+    Exited,
+    /// 异常退出且不再重启
+    Crashed,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    pub pid: u32,
+    pub state: ServerState,
+    /// 最近一次启动的时间，unix 时间戳（秒）
+    pub start_time: u64,
+    /// 运行时长（秒），进程未运行时为 0
+    pub uptime: u64,
+    pub exit_code: Option<i32>,
+    /// 导致进程退出的信号
+    pub signal: Option<i32>,
+    pub restarts: u32,
+    /// 上一次退出是否因为资源限制
+    pub limit_hit: Option<LimitHit>,
+    /// 所有子孙进程的 pid
+    pub children: Vec<u32>,
+    pub config: StartBody,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NameQuery {
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+fn default_lines() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LogsQuery {
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// 回放最近的行数
+    #[serde(default = "default_lines")]
+    pub lines: usize,
+    /// 是否通过 Server-Sent Events 持续推送
+    #[serde(default)]
+    pub follow: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StopQuery {
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// 首先发送的信号，默认 SIGTERM
+    pub signal: Option<String>,
+    /// 等待进程退出的时间，超时后发送 SIGKILL
+    pub timeout_ms: Option<u64>,
+}
+
+impl StopQuery {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            signal: None,
+            timeout_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StopInfo {
+    pub name: String,
+    pub pid: u32,
+    /// 是否因超时而强制结束
+    pub escalated: bool,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct DnsBody {
-    pub dns: String,
+    /// IPv4 或 IPv6 地址，按顺序使用
+    pub servers: Vec<String>,
+    #[serde(default)]
+    pub search_domains: Vec<String>,
+}
+
+/// GET /dns 的返回值
+#[derive(Debug, Serialize)]
+pub struct DnsInfo {
+    pub backend: String,
+    /// DNS 是否由本服务设置，还未还原
+    pub owned: bool,
+    /// 本服务设置的 DNS
+    pub applied: Option<DnsBody>,
+    /// 还原时使用的原始设置
+    pub saved: Option<SavedDns>,
+    pub resolvers: Vec<ResolverInfo>,
+}
+
+/// 某个网卡或网络服务当前的 DNS
+#[derive(Debug, Serialize)]
+pub struct ResolverInfo {
+    pub name: String,
+    pub servers: Vec<String>,
+    pub domains: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -18,3 +289,46 @@ pub struct JsonResponse<T: Serialize> {
     pub msg: String,
     pub data: Option<T>,
 }
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ReloadInfo {
+    /// 配置文件的版本
+    pub version: u32,
+    /// 监听地址有变化，需要重启服务才能生效
+    pub restart_required: bool,
+}
+
+/// 缺少或错误的 token
+pub const CODE_UNAUTHORIZED: u64 = 401;
+/// 没有权限，如启动的程序不在允许列表中
+pub const CODE_FORBIDDEN: u64 = 403;
+/// 当前平台不支持该功能
+pub const CODE_UNSUPPORTED: u64 = 501;
+
+/// 带有错误码的错误，其余错误统一返回 400
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: u64,
+    pub msg: String,
+}
+
+impl ApiError {
+    pub fn new(code: u64, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn forbidden(msg: impl Into<String>) -> anyhow::Error {
+        Self::new(CODE_FORBIDDEN, msg).into()
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+impl std::error::Error for ApiError {}
@@ -0,0 +1,533 @@
+use super::{run, DnsBackend};
+use crate::service::config::{Config, DnsBackendKind};
+use crate::service::data::ResolverInfo;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// systemd-resolved 的 stub resolver
+const RESOLVED_STUB: &str = "127.0.0.53";
+/// 写入 resolv.conf 的第一行，再次修改时去掉
+const RESOLV_CONF_HEADER: &str = "# Generated by desktop-service";
+
+/// 修改前的 DNS 设置，按修改时使用的后端记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum SavedDns {
+    SystemdResolved {
+        link: String,
+        dns: Vec<String>,
+        domains: Vec<String>,
+    },
+    /// 通过 `nmcli device modify` 修改的只是运行时配置，`reapply` 即可还原
+    NetworkManager { device: String, dns: Vec<String> },
+    ResolvConf {
+        path: PathBuf,
+        content: String,
+        /// 原来是符号链接时的目标
+        symlink: Option<PathBuf>,
+    },
+}
+
+impl SavedDns {
+    /// 修改时使用的后端
+    pub fn backend(&self) -> Box<dyn DnsBackend> {
+        match self {
+            SavedDns::SystemdResolved { link, .. } => {
+                Box::new(SystemdResolved { link: link.clone() })
+            }
+            SavedDns::NetworkManager { device, .. } => Box::new(NetworkManager {
+                device: device.clone(),
+            }),
+            SavedDns::ResolvConf { path, .. } => Box::new(ResolvConf { path: path.clone() }),
+        }
+    }
+}
+
+/// 按配置选择后端，`auto` 时依次检测 NetworkManager、systemd-resolved，
+/// 都未使用时直接修改 resolv.conf
+pub fn detect() -> Result<Box<dyn DnsBackend>> {
+    let config = Config::current();
+    let resolv_conf = &config.dns.resolv_conf;
+    let kind = match config.dns.backend {
+        DnsBackendKind::Auto if network_manager_running() => DnsBackendKind::NetworkManager,
+        DnsBackendKind::Auto if resolved_in_use(resolv_conf) => DnsBackendKind::SystemdResolved,
+        DnsBackendKind::Auto => DnsBackendKind::ResolvConf,
+        kind => kind,
+    };
+    let backend: Box<dyn DnsBackend> = match kind {
+        DnsBackendKind::SystemdResolved => Box::new(SystemdResolved {
+            link: default_interface()?,
+        }),
+        DnsBackendKind::NetworkManager => Box::new(NetworkManager {
+            device: default_interface()?,
+        }),
+        _ => Box::new(ResolvConf {
+            path: resolv_conf.clone(),
+        }),
+    };
+    Ok(backend)
+}
+
+fn network_manager_running() -> bool {
+    run("nmcli", &["-t", "-f", "RUNNING", "general"]).is_ok_and(|out| out.trim() == "running")
+}
+
+/// resolv.conf 指向 systemd-resolved 的 stub 且 resolvectl 可用
+fn resolved_in_use(resolv_conf: &Path) -> bool {
+    let linked = fs::canonicalize(resolv_conf)
+        .is_ok_and(|target| target.starts_with("/run/systemd/resolve"));
+    let stub = fs::read_to_string(resolv_conf).is_ok_and(|content| {
+        nameservers(&content)
+            .iter()
+            .any(|server| server == RESOLVED_STUB)
+    });
+    (linked || stub) && run("resolvectl", &["status"]).is_ok()
+}
+
+/// 通过 resolvectl 修改默认路由所在链路
+struct SystemdResolved {
+    link: String,
+}
+
+impl DnsBackend for SystemdResolved {
+    fn name(&self) -> &'static str {
+        "systemd-resolved"
+    }
+
+    fn save(&self) -> Result<SavedDns> {
+        let link = &self.link;
+        Ok(SavedDns::SystemdResolved {
+            link: link.clone(),
+            dns: resolvectl_values(&run("resolvectl", &["dns", link])?),
+            domains: resolvectl_values(&run("resolvectl", &["domain", link])?),
+        })
+    }
+
+    fn apply(&self, servers: &[IpAddr], search_domains: &[String]) -> Result<()> {
+        let servers: Vec<String> = servers.iter().map(ToString::to_string).collect();
+        let mut args = vec!["dns", self.link.as_str()];
+        args.extend(servers.iter().map(String::as_str));
+        run("resolvectl", &args)?;
+
+        let search_domains = if search_domains.is_empty() {
+            resolvectl_values(&run("resolvectl", &["domain", &self.link])?)
+        } else {
+            search_domains.to_vec()
+        };
+        // 所有域名都经由该链路解析
+        let mut args = vec!["domain", self.link.as_str(), "~."];
+        args.extend(
+            search_domains
+                .iter()
+                .map(String::as_str)
+                .filter(|domain| *domain != "~."),
+        );
+        run("resolvectl", &args)?;
+        Ok(())
+    }
+
+    fn restore(&self, saved: &SavedDns) -> Result<()> {
+        let SavedDns::SystemdResolved { dns, domains, .. } = saved else {
+            return Ok(());
+        };
+        // 不带值的 `resolvectl dns LINK` 只会打印当前设置，需要先清除再设置原来的值
+        run("resolvectl", &["revert", &self.link])?;
+        if !dns.is_empty() {
+            let mut args = vec!["dns", self.link.as_str()];
+            args.extend(dns.iter().map(String::as_str));
+            run("resolvectl", &args)?;
+        }
+        if !domains.is_empty() {
+            let mut args = vec!["domain", self.link.as_str()];
+            args.extend(domains.iter().map(String::as_str));
+            run("resolvectl", &args)?;
+        }
+        Ok(())
+    }
+
+    fn resolvers(&self) -> Result<Vec<ResolverInfo>> {
+        let mut resolvers: Vec<ResolverInfo> = resolvectl_links(&run("resolvectl", &["dns"])?)
+            .into_iter()
+            .map(|(name, servers)| ResolverInfo {
+                name,
+                servers,
+                domains: Vec::new(),
+            })
+            .collect();
+        for (name, domains) in resolvectl_links(&run("resolvectl", &["domain"])?) {
+            match resolvers.iter_mut().find(|r| r.name == name) {
+                Some(resolver) => resolver.domains = domains,
+                None => resolvers.push(ResolverInfo {
+                    name,
+                    servers: Vec::new(),
+                    domains,
+                }),
+            }
+        }
+        Ok(resolvers)
+    }
+}
+
+/// 通过 nmcli 修改默认路由所在网卡的运行时配置
+struct NetworkManager {
+    device: String,
+}
+
+impl DnsBackend for NetworkManager {
+    fn name(&self) -> &'static str {
+        "NetworkManager"
+    }
+
+    fn save(&self) -> Result<SavedDns> {
+        let out = run(
+            "nmcli",
+            &[
+                "-t",
+                "-g",
+                "IP4.DNS,IP6.DNS",
+                "device",
+                "show",
+                &self.device,
+            ],
+        )?;
+        let dns = out
+            .lines()
+            .flat_map(nmcli_fields)
+            .flat_map(|field| {
+                field
+                    .split(|c: char| c.is_whitespace() || c == '|' || c == ',')
+                    .filter(|s| s.parse::<IpAddr>().is_ok())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Ok(SavedDns::NetworkManager {
+            device: self.device.clone(),
+            dns,
+        })
+    }
+
+    fn apply(&self, servers: &[IpAddr], search_domains: &[String]) -> Result<()> {
+        let join = |ipv4: bool| {
+            servers
+                .iter()
+                .filter(|ip| ip.is_ipv4() == ipv4)
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let (v4, v6) = (join(true), join(false));
+        let mut args = vec![
+            "device",
+            "modify",
+            self.device.as_str(),
+            "ipv4.dns",
+            &v4,
+            "ipv4.ignore-auto-dns",
+            "yes",
+            "ipv6.dns",
+            &v6,
+            "ipv6.ignore-auto-dns",
+            "yes",
+        ];
+        let search = search_domains.join(",");
+        if !search_domains.is_empty() {
+            args.extend(["ipv4.dns-search", &search, "ipv6.dns-search", &search]);
+        }
+        run("nmcli", &args)?;
+        Ok(())
+    }
+
+    fn restore(&self, _saved: &SavedDns) -> Result<()> {
+        run("nmcli", &["device", "reapply", &self.device])?;
+        Ok(())
+    }
+
+    fn resolvers(&self) -> Result<Vec<ResolverInfo>> {
+        let out = run(
+            "nmcli",
+            &[
+                "-t",
+                "-f",
+                "GENERAL.DEVICE,IP4.DNS,IP6.DNS,IP4.DOMAIN,IP6.DOMAIN",
+                "device",
+                "show",
+            ],
+        )?;
+        let mut resolvers: Vec<ResolverInfo> = Vec::new();
+        for line in out.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            // 简洁模式下值中的冒号会被转义
+            let value = value.replace("\\:", ":");
+            let field = key.split('[').next().unwrap_or(key);
+            match (field, resolvers.last_mut()) {
+                ("GENERAL.DEVICE", _) => resolvers.push(ResolverInfo {
+                    name: value,
+                    servers: Vec::new(),
+                    domains: Vec::new(),
+                }),
+                ("IP4.DNS" | "IP6.DNS", Some(resolver)) => resolver.servers.push(value),
+                ("IP4.DOMAIN" | "IP6.DOMAIN", Some(resolver)) => resolver.domains.push(value),
+                _ => {}
+            }
+        }
+        resolvers.retain(|r| !r.servers.is_empty() || !r.domains.is_empty());
+        Ok(resolvers)
+    }
+}
+
+/// 直接改写 resolv.conf，保留其中 nameserver 以外的配置
+struct ResolvConf {
+    path: PathBuf,
+}
+
+impl DnsBackend for ResolvConf {
+    fn name(&self) -> &'static str {
+        "resolv.conf"
+    }
+
+    fn save(&self) -> Result<SavedDns> {
+        let path = &self.path;
+        let symlink = fs::symlink_metadata(path)
+            .ok()
+            .filter(|meta| meta.file_type().is_symlink())
+            .and_then(|_| fs::read_link(path).ok());
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read `{}`", path.display()))?;
+        Ok(SavedDns::ResolvConf {
+            path: path.clone(),
+            content,
+            symlink,
+        })
+    }
+
+    fn apply(&self, servers: &[IpAddr], search_domains: &[String]) -> Result<()> {
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read `{}`", self.path.display()))?;
+        let mut new = format!("{RESOLV_CONF_HEADER}\n");
+        for server in servers {
+            new.push_str(&format!("nameserver {server}\n"));
+        }
+        if !search_domains.is_empty() {
+            new.push_str(&format!("search {}\n", search_domains.join(" ")));
+        }
+        for line in content.lines() {
+            let replaced = is_nameserver(line)
+                || line == RESOLV_CONF_HEADER
+                || (!search_domains.is_empty() && is_search(line));
+            if !replaced {
+                new.push_str(line);
+                new.push('\n');
+            }
+        }
+        replace_file(&self.path, &new)
+    }
+
+    fn restore(&self, saved: &SavedDns) -> Result<()> {
+        let SavedDns::ResolvConf {
+            content, symlink, ..
+        } = saved
+        else {
+            return Ok(());
+        };
+        let path = &self.path;
+        match symlink {
+            Some(target) => {
+                let tmp = tmp_path(path);
+                let _ = fs::remove_file(&tmp);
+                std::os::unix::fs::symlink(target, &tmp)
+                    .and_then(|_| fs::rename(&tmp, path))
+                    .with_context(|| format!("failed to restore `{}`", path.display()))
+            }
+            None => replace_file(path, content),
+        }
+    }
+
+    fn resolvers(&self) -> Result<Vec<ResolverInfo>> {
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read `{}`", self.path.display()))?;
+        let domains = content
+            .lines()
+            // glibc 以最后一个 `search` 或 `domain` 为准
+            .rfind(|line| is_search(line))
+            .into_iter()
+            .flat_map(|line| line.split_whitespace().skip(1))
+            .map(String::from)
+            .collect();
+        Ok(vec![ResolverInfo {
+            name: self.path.display().to_string(),
+            servers: nameservers(&content),
+            domains,
+        }])
+    }
+}
+
+/// 写入临时文件后重命名，避免其他进程读到一半的内容
+fn replace_file(path: &Path, content: &str) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let tmp = tmp_path(path);
+    let _ = fs::remove_file(&tmp);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .open(&tmp)
+        .with_context(|| format!("failed to write `{}`", tmp.display()))?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace `{}`", path.display()))
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".desktop-service.tmp");
+    PathBuf::from(name)
+}
+
+fn is_nameserver(line: &str) -> bool {
+    line.split_whitespace().next() == Some("nameserver")
+}
+
+fn is_search(line: &str) -> bool {
+    matches!(line.split_whitespace().next(), Some("search" | "domain"))
+}
+
+fn nameservers(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter(|line| is_nameserver(line))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect()
+}
+
+/// 按未转义的 `:` 拆分 nmcli 简洁模式的一行输出，IPv6 地址中的冒号会被转义为 `\:`
+fn nmcli_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match c {
+            '\\' => field.extend(chars.next()),
+            ':' => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    fields
+}
+
+/// 只查询一个链路时的值
+fn resolvectl_values(output: &str) -> Vec<String> {
+    resolvectl_links(output)
+        .into_iter()
+        .flat_map(|(_, values)| values)
+        .collect()
+}
+
+/// 解析 `Global: ...` 和 `Link 2 (eth0): 1.1.1.1 8.8.8.8`，链路用网卡名表示
+fn resolvectl_links(output: &str) -> Vec<(String, Vec<String>)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, values) = line.split_once(": ").or_else(|| line.split_once(':'))?;
+            let name = match (name.find('('), name.strip_suffix(')')) {
+                (Some(start), Some(name)) => &name[start + 1..],
+                _ => name,
+            };
+            let values = values.split_whitespace().map(String::from).collect();
+            Some((name.trim().to_string(), values))
+        })
+        .collect()
+}
+
+/// 默认路由所在的网卡，有多条时取 metric 最小的
+fn default_interface() -> Result<String> {
+    let routes = fs::read_to_string("/proc/net/route").context("failed to read routes")?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [iface, "00000000", _, _, _, _, metric, "00000000", ..] => {
+                    Some((metric.parse::<u32>().unwrap_or(u32::MAX), iface.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .min()
+        .map(|(_, iface)| iface)
+        .context("no default route found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_resolvectl_links() {
+        let output = "Global: 1.1.1.1\nLink 2 (eth0): 192.168.1.1 fe80::1\nLink 3 (wlan0):\n";
+        assert_eq!(
+            resolvectl_links(output),
+            vec![
+                ("Global".to_string(), vec!["1.1.1.1".to_string()]),
+                (
+                    "eth0".to_string(),
+                    vec!["192.168.1.1".to_string(), "fe80::1".to_string()]
+                ),
+                ("wlan0".to_string(), vec![]),
+            ]
+        );
+        assert_eq!(
+            resolvectl_values("Link 2 (eth0): ~. example.com\n"),
+            vec!["~.", "example.com"]
+        );
+    }
+
+    #[test]
+    fn parse_nameservers() {
+        let content =
+            "# comment\nnameserver 1.1.1.1\nsearch lan\n  nameserver  ::1 \nnameservers x\n";
+        assert_eq!(nameservers(content), vec!["1.1.1.1", "::1"]);
+    }
+
+    #[test]
+    fn parse_nmcli_fields() {
+        assert_eq!(
+            nmcli_fields("1.1.1.1 | 8.8.8.8:fe80\\:\\:1"),
+            vec!["1.1.1.1 | 8.8.8.8", "fe80::1"]
+        );
+        assert_eq!(nmcli_fields(""), vec![""]);
+    }
+
+    #[test]
+    fn resolv_conf_round_trip() {
+        let dir = std::env::temp_dir().join(format!("desktop-service-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("resolv.conf");
+        let original = "nameserver 192.168.1.1\nsearch lan\noptions edns0\n";
+        fs::write(&path, original).unwrap();
+
+        let backend = ResolvConf { path: path.clone() };
+        let saved = backend.save().unwrap();
+        let servers = ["1.1.1.1".parse().unwrap(), "::1".parse().unwrap()];
+        backend.apply(&servers, &["example.com".into()]).unwrap();
+
+        let applied = fs::read_to_string(&path).unwrap();
+        assert_eq!(nameservers(&applied), vec!["1.1.1.1", "::1"]);
+        assert!(applied.contains("search example.com\n"));
+        assert!(!applied.contains("search lan"));
+        assert!(applied.contains("options edns0\n"));
+
+        backend.restore(&saved).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use super::{run, DnsBackend};
+use crate::service::data::ResolverInfo;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// 修改前的 DNS 设置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum SavedDns {
+    /// 为空表示原来没有手动设置
+    NetworkSetup {
+        service: String,
+        dns: Vec<String>,
+        #[serde(default)]
+        domains: Vec<String>,
+    },
+}
+
+impl SavedDns {
+    /// 修改时使用的后端
+    pub fn backend(&self) -> Box<dyn DnsBackend> {
+        match self {
+            SavedDns::NetworkSetup { service, .. } => Box::new(NetworkSetup {
+                service: service.clone(),
+            }),
+        }
+    }
+}
+
+/// 默认路由所在的网络服务
+pub fn detect() -> Result<Box<dyn DnsBackend>> {
+    let service = default_network_service().or_else(|_e| default_network_service_by_ns())?;
+    Ok(Box::new(NetworkSetup { service }))
+}
+
+/// 通过 networksetup 修改网络服务的 DNS
+struct NetworkSetup {
+    service: String,
+}
+
+impl DnsBackend for NetworkSetup {
+    fn name(&self) -> &'static str {
+        "networksetup"
+    }
+
+    fn save(&self) -> Result<SavedDns> {
+        Ok(SavedDns::NetworkSetup {
+            service: self.service.clone(),
+            dns: listed(&networksetup(&["-getdnsservers", &self.service])?),
+            domains: listed(&networksetup(&["-getsearchdomains", &self.service])?),
+        })
+    }
+
+    fn apply(&self, servers: &[IpAddr], search_domains: &[String]) -> Result<()> {
+        let servers: Vec<String> = servers.iter().map(ToString::to_string).collect();
+        self.set("-setdnsservers", &servers)?;
+        if !search_domains.is_empty() {
+            self.set("-setsearchdomains", search_domains)?;
+        }
+        Ok(())
+    }
+
+    fn restore(&self, saved: &SavedDns) -> Result<()> {
+        let SavedDns::NetworkSetup { dns, domains, .. } = saved;
+        self.set("-setdnsservers", dns)?;
+        self.set("-setsearchdomains", domains)?;
+        Ok(())
+    }
+
+    /// 所有已启用的网络服务
+    fn resolvers(&self) -> Result<Vec<ResolverInfo>> {
+        let stdout = networksetup(&["-listallnetworkservices"])?;
+        let mut resolvers = Vec::new();
+        // 第一行是提示，`*` 开头的服务已停用
+        for service in stdout.lines().skip(1) {
+            if service.is_empty() || service.starts_with('*') {
+                continue;
+            }
+            resolvers.push(ResolverInfo {
+                name: service.into(),
+                servers: listed(&networksetup(&["-getdnsservers", service])?),
+                domains: listed(&networksetup(&["-getsearchdomains", service])?),
+            });
+        }
+        Ok(resolvers)
+    }
+}
+
+impl NetworkSetup {
+    /// 列表为空时清除手动设置
+    fn set(&self, option: &str, values: &[String]) -> Result<()> {
+        let mut args = vec![option, self.service.as_str()];
+        if values.is_empty() {
+            args.push("Empty");
+        } else {
+            args.extend(values.iter().map(String::as_str));
+        }
+        networksetup(&args)?;
+        Ok(())
+    }
+}
+
+/// `-getdnsservers` / `-getsearchdomains` 的输出，没有设置时返回空
+fn listed(output: &str) -> Vec<String> {
+    if output.trim().starts_with("There aren't any") {
+        return Vec::new();
+    }
+    output.split_whitespace().map(String::from).collect()
+}
+
+/// networksetup 的部分错误退出码为 0，只在输出中提示
+fn networksetup(args: &[&str]) -> Result<String> {
+    let output = run("networksetup", args)?;
+    if let Some(line) = output.lines().find(|line| line.starts_with("** Error")) {
+        bail!(
+            "`networksetup {}` failed: {}",
+            args.join(" "),
+            line.trim_start_matches("** Error:").trim()
+        );
+    }
+    Ok(output)
+}
+
+fn default_network_service() -> Result<String> {
+    use std::net::{SocketAddr, UdpSocket};
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect("1.1.1.1:80")?;
+    let ip = socket.local_addr()?.ip();
+    let addr = SocketAddr::new(ip, 0);
+
+    let interfaces = interfaces::Interface::get_all()?;
+    let interface = interfaces
+        .into_iter()
+        .find(|i| i.addresses.iter().any(|a| a.addr == Some(addr)))
+        .map(|i| i.name.to_owned());
+
+    match interface {
+        Some(interface) => get_server_by_order(interface),
+        None => bail!("No network service found"),
+    }
+}
+
+fn default_network_service_by_ns() -> Result<String> {
+    let stdout = networksetup(&["-listallnetworkservices"])?;
+    let mut lines = stdout.split('\n');
+    lines.next(); // ignore the tips
+
+    // get the first service
+    match lines.next() {
+        Some(line) if !line.is_empty() => Ok(line.into()),
+        _ => bail!("No network service found"),
+    }
+}
+
+fn get_server_by_order(device: String) -> Result<String> {
+    let services = listnetworkserviceorder()?;
+    let service = services
+        .into_iter()
+        .find(|(_, _, d)| d == &device)
+        .map(|(s, _, _)| s);
+    match service {
+        Some(service) => Ok(service),
+        None => bail!("No network service found"),
+    }
+}
+
+fn listnetworkserviceorder() -> Result<Vec<(String, String, String)>> {
+    let stdout = networksetup(&["-listnetworkserviceorder"])?;
+
+    let mut lines = stdout.split('\n');
+    lines.next(); // ignore the tips
+
+    let mut services = Vec::new();
+    let mut p: Option<(String, String, String)> = None;
+
+    for line in lines {
+        if !line.starts_with('(') {
+            continue;
+        }
+
+        match p.take() {
+            None => {
+                let Some(ri) = line.find(')') else {
+                    continue;
+                };
+                let service = line[ri + 1..].trim();
+                p = Some((service.into(), "".into(), "".into()));
+            }
+            Some((service, _, _)) => {
+                let line = &line[1..line.len() - 1];
+                let (Some(pi), Some(di)) = (line.find("Port:"), line.find(", Device:")) else {
+                    p = Some((service, "".into(), "".into()));
+                    continue;
+                };
+                let port = line[pi + 5..di].trim();
+                let device = line[di + 9..].trim();
+                services.push((service, port.into(), device.into()));
+            }
+        }
+    }
+
+    Ok(services)
+}
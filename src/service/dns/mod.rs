@@ -0,0 +1,199 @@
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod unsupported;
+
+#[cfg(target_os = "linux")]
+use self::linux as platform;
+#[cfg(target_os = "macos")]
+use self::macos as platform;
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+use self::unsupported as platform;
+
+pub use self::platform::SavedDns;
+
+use super::data::*;
+use super::state_dir;
+use super::web::DNSStatus;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use anyhow::{anyhow, bail};
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// 修改 DNS 前记录原始设置，还原后删除
+const JOURNAL_FILE: &str = "dns-journal.json";
+
+/// 修改系统 DNS 的一种方式，每个平台可以有多种
+pub trait DnsBackend {
+    fn name(&self) -> &'static str;
+    /// 读取修改前的设置，用于之后还原
+    fn save(&self) -> Result<SavedDns>;
+    /// `search_domains` 为空时保留原有的搜索域
+    fn apply(&self, servers: &[IpAddr], search_domains: &[String]) -> Result<()>;
+    fn restore(&self, saved: &SavedDns) -> Result<()>;
+    /// 各网卡或网络服务当前的设置
+    fn resolvers(&self) -> Result<Vec<ResolverInfo>>;
+}
+
+/// 当前平台无法修改 DNS
+pub fn unsupported() -> anyhow::Error {
+    ApiError::new(
+        CODE_UNSUPPORTED,
+        format!("dns is not supported on {}", std::env::consts::OS),
+    )
+    .into()
+}
+
+/// 首次修改前记录原始设置，之后沿用记录时的后端
+pub fn set_dns(servers: &[IpAddr], search_domains: &[String]) -> Result<()> {
+    let mut status = DNSStatus::global().lock();
+    let backend = match &status.saved {
+        Some(saved) => saved.backend(),
+        None => {
+            let backend = platform::detect()?;
+            status.saved = Some(backend.save()?);
+            // 先落盘再修改，服务意外退出后也能还原
+            if let Err(err) = write_journal(&status) {
+                status.saved = None;
+                return Err(err);
+            }
+            backend
+        }
+    };
+
+    let addrs: Vec<String> = servers.iter().map(ToString::to_string).collect();
+    log::info!(
+        "setting dns to {} (search {}) via {}",
+        addrs.join(" "),
+        search_domains.join(" "),
+        backend.name()
+    );
+    backend.apply(servers, search_domains)?;
+    status.applied = Some(DnsBody {
+        servers: addrs,
+        search_domains: search_domains.to_vec(),
+    });
+    Ok(())
+}
+
+/// 还原为修改前的设置，未修改过时不做任何事
+pub fn unset_dns() -> Result<()> {
+    if cfg!(not(any(target_os = "linux", target_os = "macos"))) {
+        return Err(unsupported());
+    }
+
+    let mut status = DNSStatus::global().lock();
+    let Some(saved) = &status.saved else {
+        return Ok(());
+    };
+
+    let backend = saved.backend();
+    log::info!("restoring dns via {}", backend.name());
+    backend.restore(saved)?;
+    status.saved = None;
+    status.applied = None;
+    remove_journal();
+    Ok(())
+}
+
+/// 未修改过时报告检测到的后端
+pub fn dns_info() -> Result<DnsInfo> {
+    // 复制出状态后立即释放锁，下面的命令可能执行较久
+    let (applied, saved) = {
+        let status = DNSStatus::global().lock();
+        (status.applied.clone(), status.saved.clone())
+    };
+    let backend = match &saved {
+        Some(saved) => saved.backend(),
+        None => platform::detect()?,
+    };
+    Ok(DnsInfo {
+        backend: backend.name().into(),
+        owned: saved.is_some(),
+        resolvers: backend.resolvers()?,
+        applied,
+        saved,
+    })
+}
+
+/// 执行命令，退出码非 0 时返回 stderr 中的错误信息
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|err| anyhow!("failed to run {program}: {err}"))?;
+    if !output.status.success() {
+        bail!(
+            "`{program} {}` failed with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn journal_path() -> PathBuf {
+    state_dir().join(JOURNAL_FILE)
+}
+
+/// 写入临时文件后重命名，避免留下不完整的记录
+fn write_journal(status: &DNSStatus) -> Result<()> {
+    let path = journal_path();
+    let tmp = path.with_extension("json.tmp");
+    let write = || -> Result<()> {
+        fs::create_dir_all(state_dir())?;
+        let _ = fs::remove_file(&tmp);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(status)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    };
+    write().with_context(|| format!("failed to write dns journal `{}`", path.display()))
+}
+
+fn remove_journal() {
+    match fs::remove_file(journal_path()) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::warn!("failed to remove dns journal: {err}"),
+    }
+}
+
+/// 上次运行修改过 DNS 但没有还原时，启动时还原
+/// 还原失败时保留记录，之后仍可通过 /unset_dns 重试
+pub fn restore_journal() {
+    let path = journal_path();
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => {
+            log::error!("failed to read dns journal `{}`: {err}", path.display());
+            return;
+        }
+    };
+    let status: DNSStatus = match serde_json::from_slice(&content) {
+        Ok(status) => status,
+        Err(err) => {
+            log::error!("invalid dns journal `{}`: {err}", path.display());
+            return;
+        }
+    };
+
+    log::warn!("found dns journal from a previous run, restoring");
+    *DNSStatus::global().lock() = status;
+    if let Err(err) = unset_dns() {
+        log::error!("failed to restore dns from journal: {err:#}");
+    }
+}
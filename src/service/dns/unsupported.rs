@@ -0,0 +1,17 @@
+use super::{unsupported, DnsBackend};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// 没有可用的后端，不会有保存的设置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SavedDns {}
+
+impl SavedDns {
+    pub fn backend(&self) -> Box<dyn DnsBackend> {
+        match *self {}
+    }
+}
+
+pub fn detect() -> Result<Box<dyn DnsBackend>> {
+    Err(unsupported())
+}
@@ -0,0 +1,200 @@
+use super::data::*;
+#[cfg(target_os = "linux")]
+use anyhow::{bail, Context, Result};
+use std::io;
+use std::process::ExitStatus;
+#[cfg(target_os = "linux")]
+use std::{
+    fs::{self, File, OpenOptions},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// 在 pre_exec 中设置 rlimit，需在切换用户之前调用以便设置 hard limit
+///
+/// # Safety
+/// 运行在 fork 之后、exec 之前，不能分配内存或加锁
+pub unsafe fn apply_rlimits(limits: &ResourceLimits) -> io::Result<()> {
+    macro_rules! setrlimit {
+        ($resource:expr, $value:expr) => {
+            setrlimit!($resource, $value, 0)
+        };
+        ($resource:expr, $value:expr, $extra:expr) => {
+            if let Some(value) = $value {
+                let limit = libc::rlimit {
+                    rlim_cur: value as libc::rlim_t,
+                    rlim_max: value.saturating_add($extra) as libc::rlim_t,
+                };
+                if libc::setrlimit($resource, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        };
+    }
+
+    setrlimit!(libc::RLIMIT_NOFILE, limits.open_files);
+    setrlimit!(libc::RLIMIT_AS, limits.memory);
+    // hard limit 多留一秒，超出 soft limit 时先收到 SIGXCPU 而不是 SIGKILL
+    setrlimit!(libc::RLIMIT_CPU, limits.cpu_time, 1);
+    setrlimit!(libc::RLIMIT_CORE, limits.core_dump);
+    Ok(())
+}
+
+/// 根据退出状态判断进程是否因为资源限制被结束
+pub fn limit_hit(status: Option<ExitStatus>, oom_killed: bool) -> Option<LimitHit> {
+    use std::os::unix::process::ExitStatusExt;
+
+    if oom_killed {
+        return Some(LimitHit::Memory);
+    }
+    match status.and_then(|s| s.signal()) {
+        Some(libc::SIGXCPU) => Some(LimitHit::CpuTime),
+        Some(libc::SIGXFSZ) => Some(LimitHit::FileSize),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+#[cfg(target_os = "linux")]
+const CPU_PERIOD: u64 = 100_000;
+
+/// 为 server 创建的 cgroup v2 子组，用于 memory.max / cpu.max
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl Cgroup {
+    /// 未设置 memory_max 和 cpu_max 时返回 None
+    /// 组名带上实例 id，避免与同名的旧实例冲突
+    pub fn create(name: &str, id: u64, limits: &ResourceLimits) -> Result<Option<Self>> {
+        if limits.memory_max.is_none() && limits.cpu_max.is_none() {
+            return Ok(None);
+        }
+
+        let path = delegated_root()?.join(format!("{name}.{id}"));
+        fs::create_dir_all(&path).context("failed to create cgroup")?;
+        let cgroup = Self { path };
+
+        if let Some(memory_max) = limits.memory_max {
+            cgroup.write("memory.max", &memory_max.to_string())?;
+        }
+        if let Some(cpu_max) = limits.cpu_max {
+            if cpu_max.is_nan() || cpu_max <= 0.0 {
+                bail!("invalid cpu_max `{cpu_max}`");
+            }
+            let quota = (cpu_max * CPU_PERIOD as f64) as u64;
+            cgroup.write("cpu.max", &format!("{quota} {CPU_PERIOD}"))?;
+        }
+
+        Ok(Some(cgroup))
+    }
+
+    /// 在 fork 之前打开，子进程在 pre_exec 中写入 "0" 加入该组
+    pub fn procs_file(&self) -> Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.procs"))
+            .context("failed to open cgroup.procs")
+    }
+
+    /// 因超出 memory.max 而被结束的次数
+    pub fn oom_kills(&self) -> u64 {
+        let events = fs::read_to_string(self.path.join("memory.events")).unwrap_or_default();
+        events
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|n| n.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// 结束组内所有进程，需要 5.14 及以上的内核
+    pub fn kill(&self) {
+        let _ = self.write("cgroup.kill", "1");
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        fs::write(self.path.join(file), value)
+            .with_context(|| format!("failed to write {file} of cgroup"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// 在 pre_exec 中把当前进程加入 cgroup
+///
+/// # Safety
+/// 运行在 fork 之后、exec 之前，不能分配内存或加锁
+#[cfg(target_os = "linux")]
+pub unsafe fn join_cgroup(procs: &File) -> io::Result<()> {
+    if libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1) != 1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 服务启动时、创建任何子进程之前调用
+/// 服务的 cgroup 由 systemd 委派（`Delegate=yes`）时，把服务移入其中的
+/// `supervisor` 叶子节点，否则父节点中有进程时无法启用控制器
+#[cfg(target_os = "linux")]
+pub fn enter_leaf_cgroup() -> Result<()> {
+    let own = own_cgroup()?;
+    if own.is_empty() || own.ends_with("/supervisor") {
+        return Ok(());
+    }
+
+    let leaf = Path::new(CGROUP_ROOT).join(own).join("supervisor");
+    fs::create_dir_all(&leaf).context("failed to create cgroup, is Delegate=yes set?")?;
+    fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+        .context("failed to move service into its own cgroup")?;
+    Ok(())
+}
+
+/// 子组的父节点
+/// 服务位于根 cgroup 时使用独立的 `desktop-service` 节点；
+/// 否则使用 `supervisor` 叶子节点的父节点，见 [`enter_leaf_cgroup`]
+#[cfg(target_os = "linux")]
+fn delegated_root() -> Result<PathBuf> {
+    let root = Path::new(CGROUP_ROOT);
+    let own = own_cgroup()?;
+
+    let base = if own.is_empty() {
+        fs::write(root.join("cgroup.subtree_control"), "+memory +cpu")
+            .context("failed to enable cgroup controllers")?;
+        let base = root.join("desktop-service");
+        fs::create_dir_all(&base).context("failed to create cgroup")?;
+        base
+    } else if let Some(parent) = own.strip_suffix("/supervisor") {
+        root.join(parent)
+    } else {
+        bail!("service is not in a delegated cgroup, is Delegate=yes set?");
+    };
+
+    fs::write(base.join("cgroup.subtree_control"), "+memory +cpu")
+        .context("failed to enable cgroup controllers")?;
+
+    Ok(base)
+}
+
+/// 服务自身所在的 cgroup，相对于 cgroup 根目录，位于根节点时为空
+#[cfg(target_os = "linux")]
+fn own_cgroup() -> Result<String> {
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        bail!("cgroup v2 is not available");
+    }
+
+    let own = fs::read_to_string("/proc/self/cgroup").context("failed to read cgroup")?;
+    let own = own
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .context("cgroup v2 is not available")?;
+    Ok(own.trim_start_matches('/').to_string())
+}
@@ -0,0 +1,82 @@
+use super::log_dir;
+use anyhow::Result;
+use log::LevelFilter;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::rolling_file::policy::compound::{
+    roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy,
+};
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use std::path::PathBuf;
+
+const LOG_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S%.3f)} {l:<5} {t} - {m}{n}";
+/// 单个日志文件的大小上限
+const LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// 保留的归档数量
+const LOG_KEEP: u32 = 5;
+
+/// 服务自身日志所在的目录，不允许 server 的日志写入其中
+pub fn service_log_dir() -> PathBuf {
+    log_dir().join("service")
+}
+
+/// 同时输出到滚动日志文件和 stderr，日志目录不可用时只输出到 stderr
+pub fn init() {
+    let stderr = ConsoleAppender::builder()
+        .target(Target::Stderr)
+        .encoder(Box::new(PatternEncoder::new(LOG_PATTERN)))
+        .build();
+
+    let mut builder = log4rs::Config::builder()
+        .appender(Appender::builder().build("stderr", Box::new(stderr)))
+        .logger(Logger::builder().build("hyper", LevelFilter::Warn));
+    let mut root = Root::builder().appender("stderr");
+    let file_error = match rolling_file() {
+        Ok(file) => {
+            builder = builder.appender(Appender::builder().build("file", Box::new(file)));
+            root = root.appender("file");
+            None
+        }
+        Err(err) => Some(err),
+    };
+
+    let config = builder
+        .build(root.build(LevelFilter::Info))
+        .expect("invalid log config");
+    let _ = log4rs::init_config(config);
+
+    if let Some(err) = file_error {
+        log::warn!("failed to open service log: {err:#}");
+    }
+}
+
+fn rolling_file() -> Result<RollingFileAppender> {
+    let dir = service_log_dir();
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("service.log");
+
+    let pattern = format!("{}.{{}}.gz", path.display());
+    let roller = FixedWindowRoller::builder().build(&pattern, LOG_KEEP)?;
+    let policy = CompoundPolicy::new(Box::new(SizeTrigger::new(LOG_MAX_SIZE)), Box::new(roller));
+
+    let appender = RollingFileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(LOG_PATTERN)))
+        .build(path, Box::new(policy))?;
+    Ok(appender)
+}
+
+/// 访问日志，记录每一次 API 调用
+pub fn access_log(info: warp::log::Info) {
+    let remote = info
+        .remote_addr()
+        .map_or_else(|| "unix".to_string(), |addr| addr.to_string());
+    log::info!(
+        "{} {} {} {} {:?}",
+        remote,
+        info.method(),
+        info.path(),
+        info.status().as_u16(),
+        info.elapsed()
+    );
+}
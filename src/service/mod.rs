@@ -1,14 +1,32 @@
+mod auth;
+mod config;
 mod data;
+mod dns;
+#[cfg(unix)]
+mod limits;
+mod logging;
+mod output;
+mod paths;
+mod policy;
+#[cfg(unix)]
+mod privilege;
+#[cfg(unix)]
+mod socket;
+mod supervisor;
 mod web;
 
+use self::auth::*;
+use self::config::Config;
 use self::data::*;
 use self::web::*;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::runtime::Runtime;
-use warp::Filter;
+use warp::{Filter, Reply};
 
 #[cfg(windows)]
 const SERVICE_NAME: &str = "desktop-service";
-const LISTEN_PORT: u16 = 27247;
 
 #[cfg(windows)]
 use std::{ffi::OsString, time::Duration};
@@ -26,6 +44,37 @@ use windows_service::{
 #[cfg(windows)]
 const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
 
+/// 存放策略等配置的目录，仅 root 可写
+pub fn config_dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    return PathBuf::from("/etc/desktop-service");
+    #[cfg(target_os = "macos")]
+    return PathBuf::from("/Library/Application Support/desktop-service");
+    #[cfg(windows)]
+    return PathBuf::from(
+        std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".into()),
+    )
+    .join(SERVICE_NAME);
+}
+
+/// 存放运行状态的目录，重启后仍然保留
+pub fn state_dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    return PathBuf::from("/var/lib/desktop-service");
+    #[cfg(not(target_os = "linux"))]
+    return config_dir();
+}
+
+/// 默认的日志目录
+pub fn log_dir() -> PathBuf {
+    #[cfg(target_os = "linux")]
+    return PathBuf::from("/var/log/desktop-service");
+    #[cfg(target_os = "macos")]
+    return PathBuf::from("/Library/Logs/desktop-service");
+    #[cfg(windows)]
+    return config_dir().join("logs");
+}
+
 macro_rules! wrap_response {
     ($expr: expr) => {
         match $expr {
@@ -34,11 +83,14 @@ macro_rules! wrap_response {
                 msg: "ok".into(),
                 data: Some(data),
             }),
-            Err(err) => warp::reply::json(&JsonResponse {
-                code: 400,
-                msg: format!("{err}"),
-                data: Option::<()>::None,
-            }),
+            Err(err) => {
+                log::warn!("{err:#}");
+                warp::reply::json(&JsonResponse {
+                    code: err.downcast_ref::<ApiError>().map_or(400, |e| e.code),
+                    msg: format!("{err}"),
+                    data: Option::<()>::None,
+                })
+            }
         }
     };
 }
@@ -68,57 +120,151 @@ pub async fn run_service() -> anyhow::Result<()> {
         process_id: None,
     })?;
 
+    let config = Config::load()?;
+    log::info!("desktop-service {} starting", env!("CARGO_PKG_VERSION"));
+    *Config::global().write() = Arc::new(config.clone());
+    let _ = std::fs::create_dir_all(log_dir());
+    #[cfg(unix)]
+    config::reload_on_sighup();
+    #[cfg(target_os = "linux")]
+    if let Err(err) = limits::enter_leaf_cgroup() {
+        log::warn!("memory_max and cpu_max are unavailable: {err:#}");
+    }
+    dns::restore_journal();
+
     let api_version = warp::get()
         .and(warp::path("version"))
         .map(move || wrap_response!(version()));
 
     let api_start = warp::post()
         .and(warp::path("start"))
+        .and(csrf_header())
         .and(warp::body::json())
-        .map(move |body: StartBody| wrap_response!(start(body)));
+        .then(|body: StartBody| async move { wrap_response!(start(body).await) });
 
     let api_stop = warp::post()
         .and(warp::path("stop"))
-        .map(move || wrap_response!(stop()));
+        .and(csrf_header())
+        .and(warp::query())
+        .then(|query: StopQuery| async move { wrap_response!(stop(query).await) });
 
     let api_info = warp::get()
         .and(warp::path("info"))
-        .map(move || wrap_response!(info()));
+        .and(warp::query())
+        .map(move |query: NameQuery| wrap_response!(info(&query.name)));
+
+    let api_list = warp::get()
+        .and(warp::path("list"))
+        .map(move || wrap_response!(list()));
+
+    let api_logs =
+        warp::get()
+            .and(warp::path("logs"))
+            .and(warp::query())
+            .map(|query: LogsQuery| {
+                if !query.follow {
+                    return wrap_response!(logs(&query)).into_response();
+                }
+                match follow_logs(&query) {
+                    Ok(stream) => {
+                        warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response()
+                    }
+                    Err(err) => wrap_response!(Err::<(), _>(err)).into_response(),
+                }
+            });
+
+    let api_dns = warp::get()
+        .and(warp::path("dns"))
+        .then(|| async { wrap_response!(get_dns().await) });
 
     let api_set_dns = warp::post()
         .and(warp::path("set_dns"))
+        .and(csrf_header())
         .and(warp::body::json())
         .map(move |body: DnsBody| wrap_response!(set_dns(body)));
 
     let api_unset_dns = warp::post()
         .and(warp::path("unset_dns"))
+        .and(csrf_header())
         .map(|| wrap_response!(unset_dns()));
 
-    warp::serve(
-        api_version
-            .or(api_start)
+    let api_reload = warp::post()
+        .and(warp::path("reload"))
+        .and(csrf_header())
+        .map(|| wrap_response!(reload()));
+
+    // 除 /version 外都需要认证
+    let api = authorized().and(
+        api_start
             .or(api_stop)
             .or(api_info)
+            .or(api_list)
+            .or(api_logs)
+            .or(api_dns)
             .or(api_set_dns)
-            .or(api_unset_dns),
-    )
-    .run(([127, 0, 0, 1], LISTEN_PORT))
-    .await;
+            .or(api_unset_dns)
+            .or(api_reload),
+    );
+
+    let routes = local_client()
+        .and(api_version.or(api))
+        .recover(handle_rejection)
+        .with(warp::log::custom(logging::access_log));
+
+    let tcp_server = if config.tcp.enabled {
+        let addr = SocketAddr::new(config.tcp.address, config.tcp.port);
+        let (_, server) = warp::serve(routes.clone())
+            .try_bind_ephemeral(addr)
+            .map_err(|err| anyhow::anyhow!("failed to listen on {addr}: {err}"))?;
+        Some(server)
+    } else {
+        None
+    };
+
+    #[cfg(unix)]
+    let unix_server = match &config.unix_socket {
+        Some(socket) => Some(socket::UnixServer::bind(socket)?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let unix_server: Option<()> = None;
+
+    if tcp_server.is_none() && unix_server.is_none() {
+        anyhow::bail!("neither tcp nor unix_socket is enabled");
+    }
+
+    let tcp = async {
+        if let Some(server) = tcp_server {
+            server.await;
+        }
+    };
+    let unix = async {
+        #[cfg(unix)]
+        if let Some(server) = unix_server {
+            server.serve(routes.clone()).await;
+        }
+    };
 
+    tokio::join!(tcp, unix);
     Ok(())
 }
 
 /// Service Main function
 #[cfg(windows)]
 pub fn main() -> Result<()> {
+    logging::init();
     service_dispatcher::start(SERVICE_NAME, ffi_service_main)
 }
 
 #[cfg(not(windows))]
 pub fn main() {
+    logging::init();
     if let Ok(rt) = Runtime::new() {
         rt.block_on(async {
-            let _ = run_service().await;
+            if let Err(err) = run_service().await {
+                log::error!("failed to run service: {err:#}");
+                std::process::exit(1);
+            }
         });
     }
 }
@@ -130,7 +276,10 @@ define_windows_service!(ffi_service_main, my_service_main);
 pub fn my_service_main(_arguments: Vec<OsString>) {
     if let Ok(rt) = Runtime::new() {
         rt.block_on(async {
-            let _ = run_service().await;
+            if let Err(err) = run_service().await {
+                log::error!("failed to run service: {err:#}");
+                std::process::exit(1);
+            }
         });
     }
 }
@@ -0,0 +1,403 @@
+use super::data::*;
+use super::paths::LogDir;
+use anyhow::{Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::broadcast;
+
+/// 内存中保留的最近输出行数
+pub const LOG_BUFFER_LINES: usize = 1000;
+/// 超过该长度的行会被强制截断
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// 等待写入日志文件的最大块数，写入跟不上时丢弃后续输出
+const LOG_QUEUE_CHUNKS: usize = 256;
+
+/// 最近的输出，供 /logs 回放和实时推送
+/// 跨重启保留，便于查看崩溃前的输出
+#[derive(Debug)]
+pub struct LogBuffer {
+    lines: Mutex<VecDeque<String>>,
+    tx: broadcast::Sender<String>,
+}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(LOG_BUFFER_LINES);
+        Self {
+            lines: Mutex::new(VecDeque::with_capacity(LOG_BUFFER_LINES)),
+            tx,
+        }
+    }
+
+    fn push(&self, line: String) {
+        let mut lines = self.lines.lock();
+        if lines.len() == LOG_BUFFER_LINES {
+            lines.pop_front();
+        }
+        let _ = self.tx.send(line.clone());
+        lines.push_back(line);
+    }
+
+    /// 最近的 n 行
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        let lines = self.lines.lock();
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    /// 最近的 n 行以及之后的实时输出，两者之间不会遗漏或重复
+    pub fn follow(&self, n: usize) -> (Vec<String>, broadcast::Receiver<String>) {
+        let lines = self.lines.lock();
+        let rx = self.tx.subscribe();
+        let tail = lines
+            .iter()
+            .skip(lines.len().saturating_sub(n))
+            .cloned()
+            .collect();
+        (tail, rx)
+    }
+}
+
+/// 把子进程管道中的输出转发给写日志的线程，同时按行存入 `LogBuffer`
+pub fn pump<R>(mut reader: R, tx: LogWriter, logs: Arc<LogBuffer>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 8192];
+        let mut partial: Vec<u8> = Vec::new();
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    tx.send(buf[..n].to_vec());
+
+                    partial.extend_from_slice(&buf[..n]);
+                    while let Some(pos) = partial.iter().position(|b| *b == b'\n') {
+                        let line: Vec<u8> = partial.drain(..=pos).collect();
+                        logs.push(to_line(&line));
+                    }
+                    if partial.len() > MAX_LINE_LEN {
+                        logs.push(to_line(&partial));
+                        partial.clear();
+                    }
+                }
+            }
+        }
+        if !partial.is_empty() {
+            logs.push(to_line(&partial));
+        }
+    });
+}
+
+fn to_line(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches(['\r', '\n'])
+        .to_string()
+}
+
+/// 写日志线程的发送端，队列已满时丢弃输出并计数，不阻塞读取管道
+#[derive(Debug, Clone)]
+pub struct LogWriter {
+    tx: SyncSender<Vec<u8>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LogWriter {
+    fn send(&self, chunk: Vec<u8>) {
+        // 写日志的线程退出后仍然要继续读取，避免子进程阻塞在管道上
+        if let Err(TrySendError::Full(chunk)) = self.tx.try_send(chunk) {
+            self.dropped
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 启动写日志的线程，先归档已有的日志，所有发送端关闭后线程退出
+pub fn log_writer(path: &Path, rotation: &LogRotation) -> Result<LogWriter> {
+    let mut file = RotatingFile::open(path, rotation.clone())
+        .with_context(|| format!("failed to open log `{}`", path.display()))?;
+
+    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(LOG_QUEUE_CHUNKS);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let counter = dropped.clone();
+    std::thread::spawn(move || {
+        let mut failing = false;
+        for chunk in rx {
+            // 在日志中标记被丢弃的部分
+            let chunk = match counter.swap(0, Ordering::Relaxed) {
+                0 => chunk,
+                n => {
+                    log::warn!(
+                        "dropped {n} bytes of output for `{}`",
+                        file.path().display()
+                    );
+                    let mut marked = format!("[dropped {n} bytes of output]\n").into_bytes();
+                    marked.extend_from_slice(&chunk);
+                    marked
+                }
+            };
+            // 写入失败时也要继续读取，避免子进程阻塞在管道上
+            match file.write(&chunk) {
+                Ok(()) => failing = false,
+                Err(err) if !failing => {
+                    log::warn!("failed to write log `{}`: {err}", file.path().display());
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+
+    Ok(LogWriter { tx, dropped })
+}
+
+/// 按大小和时间轮转的日志文件
+struct RotatingFile {
+    dir: LogDir,
+    name: OsString,
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+    rotation: LogRotation,
+}
+
+impl RotatingFile {
+    /// 已有内容的日志会先归档
+    fn open(path: &Path, rotation: LogRotation) -> io::Result<Self> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid log path",
+            ));
+        };
+        let dir = LogDir::open(parent)?;
+        let name = name.to_owned();
+
+        // 归档前确认不是符号链接或硬链接，避免把其他文件的内容压缩到日志目录
+        let exists = match dir.open_archive(&name) {
+            Ok(file) => file.metadata()?.len() > 0,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err),
+        };
+        if exists {
+            rotate(&dir, &name, &rotation)?;
+        }
+
+        let file = dir.open_log(&name)?;
+        let meta = file.metadata()?;
+        let opened_at = match meta.len() {
+            0 => SystemTime::now(),
+            _ => meta.created().or_else(|_| meta.modified())?,
+        };
+
+        Ok(Self {
+            dir,
+            name,
+            file,
+            size: meta.len(),
+            opened_at,
+            rotation,
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.should_rotate(buf.len() as u64) {
+            if let Err(err) = self.rotate() {
+                // 继续追加到当前文件，到下一个周期再重试，而不是每次写入都重试
+                log::warn!("failed to rotate log `{}`: {err}", self.path().display());
+            }
+            self.size = 0;
+            self.opened_at = SystemTime::now();
+        }
+
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let result = rotate(&self.dir, &self.name, &self.rotation);
+        // 轮转可能只完成了一部分，当前文件已被移走时需要重新打开
+        self.file = self.dir.open_log(&self.name)?;
+        result
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.path().join(&self.name)
+    }
+
+    fn should_rotate(&self, incoming: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let max_size = self.rotation.max_size;
+        if max_size != 0 && self.size + incoming > max_size {
+            return true;
+        }
+        let max_age = Duration::from_secs(self.rotation.max_age_secs);
+        !max_age.is_zero() && self.opened_at.elapsed().unwrap_or_default() >= max_age
+    }
+}
+
+/// 归档当前日志：`log.1` 为最新的归档，超出 `keep` 的归档会被删除
+fn rotate(dir: &LogDir, name: &OsStr, rotation: &LogRotation) -> io::Result<()> {
+    let keep = rotation.keep;
+    if keep == 0 {
+        return dir.remove(name);
+    }
+
+    for suffix in ["", ".gz"] {
+        let _ = dir.remove(&archive_name(name, keep, suffix));
+    }
+    for i in (1..keep).rev() {
+        for suffix in ["", ".gz"] {
+            let from = archive_name(name, i, suffix);
+            match dir.rename(&from, &archive_name(name, i + 1, suffix)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+    }
+
+    let first = archive_name(name, 1, "");
+    dir.rename(name, &first)?;
+
+    if rotation.gzip {
+        let mut input = dir.open_archive(&first)?;
+        // 上面已删除同名文件，create_new 不会跟随新出现的符号链接
+        let output = dir.create_new(&archive_name(name, 1, ".gz"))?;
+        let mut encoder = GzEncoder::new(output, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?;
+        dir.remove(&first)?;
+    }
+
+    Ok(())
+}
+
+fn archive_name(name: &OsStr, index: u32, suffix: &str) -> OsString {
+    let mut name = name.to_owned();
+    name.push(format!(".{index}{suffix}"));
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::config::Config;
+    use std::fs;
+    use std::io::Read;
+
+    /// 日志需要位于 `log_dirs` 中，所有测试共用临时目录作为 `log_dirs`
+    fn test_dir(name: &str) -> PathBuf {
+        let root = fs::canonicalize(std::env::temp_dir()).unwrap();
+        let mut config = (*Config::current()).clone();
+        config.paths.log_dirs = vec![root.clone()];
+        *Config::global().write() = Arc::new(config);
+
+        let dir = root.join(format!(
+            "desktop-service-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rotation(max_size: u64, keep: u32, gzip: bool) -> LogRotation {
+        LogRotation {
+            max_size,
+            max_age_secs: 0,
+            keep,
+            gzip,
+        }
+    }
+
+    fn archive(path: &Path, index: u32, suffix: &str) -> PathBuf {
+        path.with_file_name(archive_name(path.file_name().unwrap(), index, suffix))
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = test_dir("size");
+        let path = dir.join("server.log");
+        let mut file = RotatingFile::open(&path, rotation(10, 2, false)).unwrap();
+        for chunk in ["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n", "dddddddd\n"] {
+            file.write(chunk.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddddd\n");
+        assert_eq!(
+            fs::read_to_string(archive(&path, 1, "")).unwrap(),
+            "cccccccc\n"
+        );
+        assert_eq!(
+            fs::read_to_string(archive(&path, 2, "")).unwrap(),
+            "bbbbbbbb\n"
+        );
+        assert!(!archive(&path, 3, "").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn archive_on_open() {
+        let dir = test_dir("open");
+        let path = dir.join("server.log");
+        fs::write(&path, "old\n").unwrap();
+
+        let mut file = RotatingFile::open(&path, rotation(0, 3, true)).unwrap();
+        file.write(b"new\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(!archive(&path, 1, "").exists());
+
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(fs::File::open(archive(&path, 1, ".gz")).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "old\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drop_when_queue_full() {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let writer = LogWriter {
+            tx,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        writer.send(b"kept\n".to_vec());
+        writer.send(b"dropped\n".to_vec());
+        assert_eq!(rx.try_recv().unwrap(), b"kept\n");
+        assert_eq!(writer.dropped.load(Ordering::Relaxed), 8);
+
+        // 写日志的线程退出后不再计数
+        drop(rx);
+        writer.send(b"closed\n".to_vec());
+        assert_eq!(writer.dropped.load(Ordering::Relaxed), 8);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_symlinked_log() {
+        let dir = test_dir("symlink");
+        let path = dir.join("server.log");
+        std::os::unix::fs::symlink("/etc/passwd", &path).unwrap();
+
+        assert!(RotatingFile::open(&path, rotation(0, 3, false)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
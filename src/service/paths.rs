@@ -0,0 +1,240 @@
+use super::config::Config;
+use super::data::*;
+use super::logging::service_log_dir;
+use anyhow::Result;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+
+/// 检查 `log_file` / `stderr_file`，返回解析过符号链接的路径
+/// 所在目录必须已存在且位于 `log_dirs` 中
+pub fn resolve_log_path(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(ApiError::forbidden(format!(
+            "log path `{}` must be absolute and must not contain `..`",
+            path.display()
+        )));
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(ApiError::forbidden(format!(
+            "invalid log path `{}`",
+            path.display()
+        )));
+    };
+
+    let parent = fs::canonicalize(parent).map_err(|err| {
+        ApiError::forbidden(format!("failed to resolve `{}`: {err}", parent.display()))
+    })?;
+    check_roots(&parent, &Config::current().paths.log_dirs, "log_dirs")?;
+    if fs::canonicalize(service_log_dir()).is_ok_and(|dir| parent.starts_with(dir)) {
+        return Err(ApiError::forbidden(format!(
+            "`{}` is reserved for the service's own logs",
+            parent.display()
+        )));
+    }
+
+    Ok(parent.join(name))
+}
+
+/// `bin_dirs` 为空时不做限制
+pub fn check_bin_path(canonical: &Path) -> Result<()> {
+    let config = Config::current();
+    if config.paths.bin_dirs.is_empty() {
+        return Ok(());
+    }
+    check_roots(canonical, &config.paths.bin_dirs, "bin_dirs")
+}
+
+fn check_roots(path: &Path, roots: &[PathBuf], key: &str) -> Result<()> {
+    let allowed = roots
+        .iter()
+        .filter_map(|root| fs::canonicalize(root).ok())
+        .any(|root| path.starts_with(root));
+    if !allowed {
+        return Err(ApiError::forbidden(format!(
+            "`{}` is outside of the allowed {key}",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// 日志所在的目录，日志及其归档的打开、重命名和删除都相对于该目录进行
+/// Unix 上从 `log_dirs` 逐级打开，中间的目录被替换为符号链接时打开失败，
+/// 不会被引导去修改其他位置的文件
+#[derive(Debug)]
+pub struct LogDir {
+    path: PathBuf,
+    #[cfg(unix)]
+    fd: OwnedFd,
+}
+
+enum OpenMode {
+    Append,
+    Read,
+    CreateNew,
+}
+
+impl LogDir {
+    /// `path` 为 [`resolve_log_path`] 返回的路径所在的目录
+    #[cfg(unix)]
+    pub fn open(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let root = Config::current()
+            .paths
+            .log_dirs
+            .iter()
+            .filter_map(|root| fs::canonicalize(root).ok())
+            .find(|root| path.starts_with(root))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "outside of the allowed log_dirs",
+                )
+            })?;
+
+        let mut fd: OwnedFd = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(&root)?
+            .into();
+        for component in path.strip_prefix(&root).unwrap_or(path).components() {
+            fd = openat(
+                &fd,
+                component.as_os_str(),
+                libc::O_RDONLY | libc::O_DIRECTORY,
+            )?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            fd,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 以追加方式打开日志：不跟随符号链接，也不写入有多个硬链接的文件，
+    /// 避免被引导去覆盖其他位置的文件
+    pub fn open_log(&self, name: &OsStr) -> io::Result<File> {
+        self.open_file(name, OpenMode::Append)
+    }
+
+    /// 只读打开已有的日志或归档，限制同 `open_log`
+    pub fn open_archive(&self, name: &OsStr) -> io::Result<File> {
+        self.open_file(name, OpenMode::Read)
+    }
+
+    /// 创建新文件，已存在（包括符号链接）时失败
+    pub fn create_new(&self, name: &OsStr) -> io::Result<File> {
+        self.open_file(name, OpenMode::CreateNew)
+    }
+
+    #[cfg(unix)]
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        let (from, to) = (c_name(from)?, c_name(to)?);
+        let fd = self.fd.as_raw_fd();
+        if unsafe { libc::renameat(fd, from.as_ptr(), fd, to.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        fs::rename(self.path.join(from), self.path.join(to))
+    }
+
+    #[cfg(unix)]
+    pub fn remove(&self, name: &OsStr) -> io::Result<()> {
+        let name = c_name(name)?;
+        if unsafe { libc::unlinkat(self.fd.as_raw_fd(), name.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn remove(&self, name: &OsStr) -> io::Result<()> {
+        fs::remove_file(self.path.join(name))
+    }
+
+    #[cfg(unix)]
+    fn open_file(&self, name: &OsStr, mode: OpenMode) -> io::Result<File> {
+        let flags = match mode {
+            OpenMode::Append => libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT,
+            OpenMode::Read => libc::O_RDONLY,
+            OpenMode::CreateNew => libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL,
+        };
+        // O_NONBLOCK 避免打开没有读端的 FIFO 时阻塞，对普通文件没有影响
+        let file = File::from(openat(&self.fd, name, flags | libc::O_NONBLOCK)?);
+        check_regular(&file.metadata()?)?;
+        Ok(file)
+    }
+
+    #[cfg(not(unix))]
+    fn open_file(&self, name: &OsStr, mode: OpenMode) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        match mode {
+            OpenMode::Append => options.create(true).append(true),
+            OpenMode::Read => options.read(true),
+            OpenMode::CreateNew => options.write(true).create_new(true),
+        };
+        let file = options.open(self.path.join(name))?;
+        check_regular(&file.metadata()?)?;
+        Ok(file)
+    }
+}
+
+/// 相对于目录打开，不跟随符号链接
+#[cfg(unix)]
+fn openat(dir: &OwnedFd, name: &OsStr, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let name = c_name(name)?;
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, 0o666 as libc::c_uint) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(unix)]
+fn c_name(name: &OsStr) -> io::Result<CString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    CString::new(name.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// 日志只能是只有一个链接的普通文件
+pub fn check_regular(meta: &Metadata) -> io::Result<()> {
+    if !meta.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a regular file",
+        ));
+    }
+    #[cfg(unix)]
+    if std::os::unix::fs::MetadataExt::nlink(meta) > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file has multiple hard links",
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use super::data::*;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// 允许启动的程序列表，为空时拒绝启动任何程序
+///
+/// ```toml
+/// [[policy.allow]]
+/// path = "/opt/app/core"
+/// sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub allow: Vec<AllowedBinary>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllowedBinary {
+    /// 比较前会解析符号链接
+    pub path: PathBuf,
+    /// 十六进制的 SHA-256，设置后额外校验程序内容
+    pub sha256: Option<String>,
+}
+
+impl Policy {
+    pub fn validate(&self) -> Result<()> {
+        for entry in &self.allow {
+            if !entry.path.is_absolute() {
+                bail!("policy path `{}` must be absolute", entry.path.display());
+            }
+            if let Some(sha256) = &entry.sha256 {
+                let sha256 = sha256.trim();
+                if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    bail!("invalid sha256 for `{}`", entry.path.display());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 校验通过后返回解析过符号链接的路径，之后应使用该路径启动
+    pub fn check(&self, bin_path: &str) -> Result<PathBuf> {
+        let canonical = fs::canonicalize(bin_path)
+            .map_err(|err| ApiError::forbidden(format!("failed to resolve `{bin_path}`: {err}")))?;
+
+        let entry = self
+            .allow
+            .iter()
+            .find(|entry| fs::canonicalize(&entry.path).is_ok_and(|path| path == canonical))
+            .ok_or_else(|| {
+                ApiError::forbidden(format!(
+                    "`{}` is not allowed by policy",
+                    canonical.display()
+                ))
+            })?;
+
+        // 校验和启动分别打开路径，路径上的任何一级可被替换时校验就失去意义
+        #[cfg(unix)]
+        check_owner_chain(&canonical)?;
+
+        if let Some(expected) = &entry.sha256 {
+            let actual = sha256(&canonical)
+                .with_context(|| format!("failed to hash `{}`", canonical.display()))?;
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                return Err(ApiError::forbidden(format!(
+                    "sha256 of `{}` does not match policy",
+                    canonical.display()
+                )));
+            }
+        }
+
+        Ok(canonical)
+    }
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// 程序及其所在的各级目录都必须属于 root 且不能被其他用户修改
+#[cfg(unix)]
+fn check_owner_chain(path: &Path) -> Result<()> {
+    path.ancestors().try_for_each(check_owner)
+}
+
+/// 文件必须属于 root 且不能被其他用户修改，否则策略形同虚设
+#[cfg(unix)]
+pub fn check_owner(path: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta =
+        fs::metadata(path).with_context(|| format!("failed to stat `{}`", path.display()))?;
+    if meta.uid() != 0 || meta.mode() & 0o022 != 0 {
+        return Err(ApiError::forbidden(format!(
+            "`{}` must be owned by root and not writable by group or others",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn allow(path: &str, sha256: Option<String>) -> Policy {
+        Policy {
+            allow: vec![AllowedBinary {
+                path: path.into(),
+                sha256,
+            }],
+        }
+    }
+
+    fn is_forbidden(result: Result<PathBuf>) -> bool {
+        result
+            .unwrap_err()
+            .downcast_ref::<ApiError>()
+            .is_some_and(|err| err.code == CODE_FORBIDDEN)
+    }
+
+    #[test]
+    fn check_allowed_path() {
+        let canonical = fs::canonicalize("/bin/sh").unwrap();
+        assert!(is_forbidden(Policy::default().check("/bin/sh")));
+        assert_eq!(allow("/bin/sh", None).check("/bin/sh").unwrap(), canonical);
+        // 比较的是解析过符号链接的路径
+        let policy = allow(canonical.to_str().unwrap(), None);
+        assert_eq!(policy.check("/bin/sh").unwrap(), canonical);
+        assert!(is_forbidden(allow("/bin/sh", None).check("/bin/ls")));
+        assert!(is_forbidden(allow("/bin/sh", None).check("/nonexistent")));
+    }
+
+    #[test]
+    fn check_sha256() {
+        let hash = sha256(Path::new("/bin/sh")).unwrap();
+        assert!(allow("/bin/sh", Some(hash.to_uppercase()))
+            .check("/bin/sh")
+            .is_ok());
+        assert!(is_forbidden(
+            allow("/bin/sh", Some("0".repeat(64))).check("/bin/sh")
+        ));
+    }
+
+    #[test]
+    fn reject_writable_parent() {
+        let dir = std::env::temp_dir().join(format!(
+            "desktop-service-test-{}-policy",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let bin = dir.join("policy-sh");
+        fs::copy("/bin/sh", &bin).unwrap();
+        let bin = bin.to_str().unwrap();
+
+        // 即使内容匹配，所在目录可被其他用户修改时也会拒绝
+        let hash = sha256(Path::new(bin)).unwrap();
+        assert!(is_forbidden(allow(bin, Some(hash)).check(bin)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn validate_entries() {
+        assert!(allow("/bin/sh", None).validate().is_ok());
+        assert!(allow("bin/sh", None).validate().is_err());
+        assert!(allow("/bin/sh", Some("abc".into())).validate().is_err());
+        assert!(allow("/bin/sh", Some("g".repeat(64))).validate().is_err());
+    }
+}
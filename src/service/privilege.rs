@@ -0,0 +1,306 @@
+use super::data::*;
+use anyhow::{bail, Context, Result};
+use std::ffi::{CStr, CString};
+use std::io;
+
+/// 子进程切换到的用户、用户组以及保留的 capabilities
+/// 在 fork 之前解析完毕，pre_exec 中只做系统调用
+#[derive(Debug)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub groups: Vec<libc::gid_t>,
+    pub user: Option<User>,
+    /// 以位图表示的 capabilities
+    #[cfg(target_os = "linux")]
+    pub caps: u64,
+}
+
+#[derive(Debug)]
+pub struct User {
+    pub name: String,
+    pub home: String,
+}
+
+impl Credentials {
+    /// 未指定 user 和 group 时返回 None，保持以 root 运行
+    pub fn resolve(body: &StartBody) -> Result<Option<Self>> {
+        if body.user.is_none() && body.group.is_none() {
+            if !body.capabilities.is_empty() {
+                bail!("capabilities require `user`");
+            }
+            return Ok(None);
+        }
+
+        let passwd = match &body.user {
+            Some(user) => Some(lookup_user(user)?),
+            None => None,
+        };
+        let gid = match (&body.group, &passwd) {
+            (Some(group), _) => lookup_group(group)?,
+            (None, Some(passwd)) => passwd.gid,
+            (None, None) => unreachable!(),
+        };
+        let uid = match &passwd {
+            Some(passwd) => passwd.uid,
+            None => unsafe { libc::getuid() },
+        };
+        let groups = match &passwd {
+            Some(passwd) => supplementary_groups(&passwd.name, gid),
+            None => vec![gid],
+        };
+
+        #[cfg(target_os = "linux")]
+        let caps = parse_capabilities(&body.capabilities)?;
+        #[cfg(not(target_os = "linux"))]
+        if !body.capabilities.is_empty() {
+            bail!("capabilities are only supported on Linux");
+        }
+
+        Ok(Some(Self {
+            uid,
+            gid,
+            groups,
+            user: passwd.map(|p| User {
+                name: p.name,
+                home: p.home,
+            }),
+            #[cfg(target_os = "linux")]
+            caps,
+        }))
+    }
+
+    /// 在 pre_exec 中调用，切换用户并保留指定的 capabilities
+    ///
+    /// # Safety
+    /// 运行在 fork 之后、exec 之前，不能分配内存或加锁
+    pub unsafe fn apply(&self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.caps != 0 && libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) != 0
+            || libc::setgid(self.gid) != 0
+            || libc::setuid(self.uid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.caps != 0 {
+            raise_ambient(self.caps)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Passwd {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub home: String,
+}
+
+/// 支持用户名或数字 uid
+pub fn lookup_user(user: &str) -> Result<Passwd> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let ret = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe {
+            libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+        Err(_) => {
+            let name = CString::new(user).context("invalid user name")?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+    };
+    if ret != 0 || result.is_null() {
+        bail!("user `{user}` not found");
+    }
+
+    let to_string = |ptr: *const libc::c_char| {
+        if ptr.is_null() {
+            return String::new();
+        }
+        unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Ok(Passwd {
+        name: to_string(pwd.pw_name),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: to_string(pwd.pw_dir),
+    })
+}
+
+/// 支持组名或数字 gid
+pub fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).context("invalid group name")?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        bail!("group `{group}` not found");
+    }
+    Ok(grp.gr_gid)
+}
+
+#[cfg(target_os = "linux")]
+fn supplementary_groups(user: &str, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    let Ok(name) = CString::new(user) else {
+        return vec![gid];
+    };
+    let mut groups: Vec<libc::gid_t> = vec![0; 256];
+    let mut count = groups.len() as libc::c_int;
+    let ret = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+    if ret < 0 {
+        return vec![gid];
+    }
+    groups.truncate(count as usize);
+    groups
+}
+
+#[cfg(not(target_os = "linux"))]
+fn supplementary_groups(_user: &str, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    vec![gid]
+}
+
+#[cfg(target_os = "linux")]
+const CAPABILITIES: &[(&str, u32)] = &[
+    ("CAP_CHOWN", 0),
+    ("CAP_DAC_OVERRIDE", 1),
+    ("CAP_DAC_READ_SEARCH", 2),
+    ("CAP_FOWNER", 3),
+    ("CAP_FSETID", 4),
+    ("CAP_KILL", 5),
+    ("CAP_SETGID", 6),
+    ("CAP_SETUID", 7),
+    ("CAP_SETPCAP", 8),
+    ("CAP_LINUX_IMMUTABLE", 9),
+    ("CAP_NET_BIND_SERVICE", 10),
+    ("CAP_NET_BROADCAST", 11),
+    ("CAP_NET_ADMIN", 12),
+    ("CAP_NET_RAW", 13),
+    ("CAP_IPC_LOCK", 14),
+    ("CAP_IPC_OWNER", 15),
+    ("CAP_SYS_MODULE", 16),
+    ("CAP_SYS_RAWIO", 17),
+    ("CAP_SYS_CHROOT", 18),
+    ("CAP_SYS_PTRACE", 19),
+    ("CAP_SYS_PACCT", 20),
+    ("CAP_SYS_ADMIN", 21),
+    ("CAP_SYS_BOOT", 22),
+    ("CAP_SYS_NICE", 23),
+    ("CAP_SYS_RESOURCE", 24),
+    ("CAP_SYS_TIME", 25),
+    ("CAP_SYS_TTY_CONFIG", 26),
+    ("CAP_MKNOD", 27),
+    ("CAP_LEASE", 28),
+    ("CAP_AUDIT_WRITE", 29),
+    ("CAP_AUDIT_CONTROL", 30),
+    ("CAP_SETFCAP", 31),
+    ("CAP_MAC_OVERRIDE", 32),
+    ("CAP_MAC_ADMIN", 33),
+    ("CAP_SYSLOG", 34),
+    ("CAP_WAKE_ALARM", 35),
+    ("CAP_BLOCK_SUSPEND", 36),
+    ("CAP_AUDIT_READ", 37),
+    ("CAP_PERFMON", 38),
+    ("CAP_BPF", 39),
+    ("CAP_CHECKPOINT_RESTORE", 40),
+];
+
+/// 支持 `CAP_NET_ADMIN` 或 `net_admin` 两种写法
+#[cfg(target_os = "linux")]
+fn parse_capabilities(names: &[String]) -> Result<u64> {
+    let mut caps = 0u64;
+    for name in names {
+        let upper = name.to_ascii_uppercase();
+        let full = if upper.starts_with("CAP_") {
+            upper
+        } else {
+            format!("CAP_{upper}")
+        };
+        match CAPABILITIES.iter().find(|(n, _)| *n == full) {
+            Some((_, bit)) => caps |= 1 << bit,
+            None => bail!("unknown capability `{name}`"),
+        }
+    }
+    Ok(caps)
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// setuid 之后只保留指定的 capabilities，并加入 ambient 集合使其在 exec 后继续生效
+#[cfg(target_os = "linux")]
+unsafe fn raise_ambient(caps: u64) -> io::Result<()> {
+    const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [caps as u32, (caps >> 32) as u32].map(|bits| CapData {
+        effective: bits,
+        permitted: bits,
+        inheritable: bits,
+    });
+    if libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for bit in 0..64u64 {
+        if caps & (1 << bit) != 0
+            && libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                bit as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+            ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use super::auth::PeerVerified;
+use super::config::UnixSocketConfig;
+use super::data::*;
+use super::privilege::{lookup_group, lookup_user};
+use anyhow::{bail, Context, Result};
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+use tokio::net::{UnixListener, UnixStream};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// 调用方的 uid/gid/pid，来自 SO_PEERCRED
+#[derive(Debug, Clone)]
+struct PeerCred {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    pid: Option<i32>,
+    /// 连接时的附加组，来自 SO_PEERGROUPS
+    groups: Vec<libc::gid_t>,
+}
+
+impl std::fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "uid={} gid={}", self.uid, self.gid)?;
+        match self.pid {
+            Some(pid) => write!(f, " pid={pid}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// 允许调用 API 的对端：root、socket 所属组的成员以及 `allow_users`
+#[derive(Debug)]
+struct PeerAcl {
+    uids: Vec<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+}
+
+impl PeerAcl {
+    fn allows(&self, peer: &PeerCred) -> bool {
+        peer.uid == 0
+            || self.uids.contains(&peer.uid)
+            || self
+                .gid
+                .is_some_and(|gid| peer.gid == gid || peer.groups.contains(&gid))
+    }
+}
+
+pub struct UnixServer {
+    listener: UnixListener,
+    acl: Arc<PeerAcl>,
+}
+
+impl UnixServer {
+    /// 创建 socket 并设置权限，已存在的旧 socket 文件会被删除
+    pub fn bind(config: &UnixSocketConfig) -> Result<Self> {
+        let path = &config.path;
+        let mode = config.mode()?;
+        let gid = match &config.group {
+            Some(group) => Some(lookup_group(group)?),
+            None => None,
+        };
+        let uids = config
+            .allow_users
+            .iter()
+            .map(|user| lookup_user(user).map(|p| p.uid))
+            .collect::<Result<Vec<_>>>()?;
+
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => {
+                fs::remove_file(path).context("failed to remove stale socket")?
+            }
+            Ok(_) => bail!("`{}` exists and is not a socket", path.display()),
+            Err(_) => {}
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("failed to create socket directory")?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to bind `{}`", path.display()))?;
+        if gid.is_some() {
+            std::os::unix::fs::chown(path, Some(0), gid).context("failed to chown socket")?;
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .context("failed to chmod socket")?;
+
+        Ok(Self {
+            listener,
+            acl: Arc::new(PeerAcl { uids, gid }),
+        })
+    }
+
+    /// 每个连接单独交给 hyper 处理，以便按请求检查并记录对端凭据
+    /// 通过检查的请求不再需要 token
+    pub async fn serve<F>(self, filter: F)
+    where
+        F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+    {
+        let service = warp::service(filter);
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    log::warn!("failed to accept connection: {err}");
+                    continue;
+                }
+            };
+            let peer = peer_cred(&stream);
+            let acl = self.acl.clone();
+            let service = service.clone();
+
+            tokio::spawn(async move {
+                let handler = service_fn(move |mut req: warp::http::Request<hyper::Body>| {
+                    let mut service = service.clone();
+                    let peer = peer.clone();
+                    let allowed = peer.as_ref().is_some_and(|peer| acl.allows(peer));
+                    match &peer {
+                        Some(peer) => {
+                            log::info!("{} {} from {peer}", req.method(), req.uri().path())
+                        }
+                        None => {
+                            log::warn!("{} {} from unknown peer", req.method(), req.uri().path())
+                        }
+                    }
+                    async move {
+                        if !allowed {
+                            return Ok(forbidden(peer));
+                        }
+                        req.extensions_mut().insert(PeerVerified);
+                        service.call(req).await
+                    }
+                });
+                let _ = Http::new()
+                    .http1_only(true)
+                    .serve_connection(stream, handler)
+                    .await;
+            });
+        }
+    }
+}
+
+fn peer_cred(stream: &UnixStream) -> Option<PeerCred> {
+    let cred = stream.peer_cred().ok()?;
+    Some(PeerCred {
+        uid: cred.uid(),
+        gid: cred.gid(),
+        pid: cred.pid(),
+        groups: peer_groups(stream, cred.uid()),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn peer_groups(stream: &UnixStream, _uid: libc::uid_t) -> Vec<libc::gid_t> {
+    use std::os::fd::AsRawFd;
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 256];
+    let mut len = (groups.len() * std::mem::size_of::<libc::gid_t>()) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERGROUPS,
+            groups.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Vec::new();
+    }
+    groups.truncate(len as usize / std::mem::size_of::<libc::gid_t>());
+    groups
+}
+
+/// macOS 没有 SO_PEERGROUPS，按 uid 查询用户所属的组
+#[cfg(target_os = "macos")]
+fn peer_groups(_stream: &UnixStream, uid: libc::uid_t) -> Vec<libc::gid_t> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() {
+        return Vec::new();
+    }
+
+    // 缓冲区不够时返回 -1，加倍后重试
+    let mut groups = vec![0; 64];
+    loop {
+        let mut len = groups.len() as libc::c_int;
+        let ret = unsafe {
+            libc::getgrouplist(pwd.pw_name, pwd.pw_gid as _, groups.as_mut_ptr(), &mut len)
+        };
+        if ret >= 0 {
+            groups.truncate(len as usize);
+            return groups.into_iter().map(|gid| gid as libc::gid_t).collect();
+        }
+        if groups.len() >= 4096 {
+            return Vec::new();
+        }
+        groups.resize(groups.len() * 2, 0);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn peer_groups(_stream: &UnixStream, _uid: libc::uid_t) -> Vec<libc::gid_t> {
+    Vec::new()
+}
+
+fn forbidden(peer: Option<PeerCred>) -> warp::reply::Response {
+    let msg = match peer {
+        Some(peer) => format!("caller {peer} is not allowed"),
+        None => "failed to get caller credentials".into(),
+    };
+    warp::reply::with_status(
+        warp::reply::json(&JsonResponse {
+            code: CODE_FORBIDDEN,
+            msg,
+            data: Option::<()>::None,
+        }),
+        StatusCode::FORBIDDEN,
+    )
+    .into_response()
+}
@@ -0,0 +1,698 @@
+use super::config::Config;
+use super::data::*;
+#[cfg(unix)]
+use super::limits::*;
+use super::output::*;
+use super::paths::{check_bin_path, resolve_log_path};
+#[cfg(unix)]
+use super::privilege::*;
+use super::web::{remove_stopped, ServerRegistry, ServerStatus};
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use sysinfo::{Pid, Process, ProcessRefreshKind, RefreshKind, Signal, System};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, watch};
+
+/// 每次 start 分配一个唯一 id，避免旧的守护任务修改新实例的状态
+pub fn next_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// 同一实例在多次重启之间共享的资源
+#[derive(Debug)]
+pub struct ServerContext {
+    pub body: StartBody,
+    pub logs: Arc<LogBuffer>,
+    /// 每个日志文件只有一个写入线程，避免各自计数、互相轮转
+    writers: Mutex<HashMap<PathBuf, LogWriter>>,
+    #[cfg(target_os = "linux")]
+    pub cgroup: Option<Cgroup>,
+}
+
+impl ServerContext {
+    pub fn new(id: u64, body: StartBody) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        let cgroup = Cgroup::create(&body.name, id, &body.limits)?;
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = id;
+            if body.limits.memory_max.is_some() || body.limits.cpu_max.is_some() {
+                anyhow::bail!("memory_max and cpu_max are only supported on Linux");
+            }
+        }
+
+        Ok(Self {
+            body,
+            logs: Arc::new(LogBuffer::new()),
+            writers: Mutex::new(HashMap::new()),
+            #[cfg(target_os = "linux")]
+            cgroup,
+        })
+    }
+
+    /// 首次使用某个日志文件时归档旧日志并启动写入线程，之后的重启复用
+    fn log_writer(&self, path: &Path) -> Result<LogWriter> {
+        let mut writers = self.writers.lock();
+        if let Some(tx) = writers.get(path) {
+            return Ok(tx.clone());
+        }
+        let tx = log_writer(path, &self.body.rotation)?;
+        writers.insert(path.to_path_buf(), tx.clone());
+        Ok(tx)
+    }
+
+    /// cgroup 中累计的 OOM kill 次数
+    fn oom_kills(&self) -> u64 {
+        #[cfg(target_os = "linux")]
+        if let Some(cgroup) = &self.cgroup {
+            return cgroup.oom_kills();
+        }
+        0
+    }
+}
+
+/// 启动前校验程序和日志路径，返回程序路径和 stdout、stderr 的日志路径
+/// 同名 server 替换旧实例前也需要先调用，校验失败时不影响旧实例
+pub fn check_spawn(body: &StartBody) -> Result<(PathBuf, LogPaths)> {
+    if let Some(key) = body
+        .env
+        .set
+        .keys()
+        .find(|key| is_loader_var(OsStr::new(key)))
+    {
+        return Err(ApiError::forbidden(format!(
+            "env `{key}` is not allowed, it would inject code into the allowed binary"
+        )));
+    }
+
+    let bin_path = Config::current().policy.check(&body.bin_path)?;
+    check_bin_path(&bin_path)?;
+
+    let log_paths = match body.stdio {
+        StdioMode::Merged => {
+            let path = resolve_log_path(&body.log_file)?;
+            (Some(path.clone()), Some(path))
+        }
+        StdioMode::Separate => {
+            let stderr_file = body
+                .stderr_file
+                .as_ref()
+                .context("stderr_file is required in separate mode")?;
+            (
+                Some(resolve_log_path(&body.log_file)?),
+                Some(resolve_log_path(stderr_file)?),
+            )
+        }
+        StdioMode::Discard => (None, None),
+    };
+
+    Ok((bin_path, log_paths))
+}
+
+type LogPaths = (Option<PathBuf>, Option<PathBuf>);
+
+/// 动态链接器读取的变量，如 `LD_PRELOAD`、`DYLD_INSERT_LIBRARIES`，
+/// 可以让通过策略校验的程序加载任意代码
+fn is_loader_var(key: &OsStr) -> bool {
+    let key = key.as_encoded_bytes();
+    key.starts_with(b"LD_") || key.starts_with(b"DYLD_")
+}
+
+/// 启动 server 进程
+/// 输出经由管道写入日志，首次启动时归档旧日志，重启时追加
+pub fn spawn_server(ctx: &ServerContext) -> Result<Child> {
+    let body = &ctx.body;
+    let logs = &ctx.logs;
+    let (bin_path, (stdout_path, stderr_path)) = check_spawn(body)?;
+    let mut command = std::process::Command::new(&bin_path);
+    command.args(&body.args);
+
+    if body.env.clear {
+        command.env_clear();
+    }
+    for key in &body.env.unset {
+        command.env_remove(key);
+    }
+    // 服务自身环境中的加载器变量同样不传给子进程
+    for (key, _) in std::env::vars_os().filter(|(key, _)| is_loader_var(key)) {
+        command.env_remove(key);
+    }
+    command.envs(&body.env.set);
+    if let Some(cwd) = &body.cwd {
+        command.current_dir(cwd);
+    }
+
+    configure_process(&mut command, ctx)?;
+
+    // 路径相同时共用同一个写入线程
+    let stdout_tx = stdout_path.map(|path| ctx.log_writer(&path)).transpose()?;
+    let stderr_tx = stderr_path.map(|path| ctx.log_writer(&path)).transpose()?;
+
+    let stdio = |tx: &Option<_>| match tx {
+        Some(_) => Stdio::piped(),
+        None => Stdio::null(),
+    };
+    command.stdout(stdio(&stdout_tx)).stderr(stdio(&stderr_tx));
+
+    let mut child = Command::from(command).spawn()?;
+    log::info!(
+        "spawned `{}` (pid {}): {} {:?}",
+        body.name,
+        child.id().unwrap_or_default(),
+        bin_path.display(),
+        body.args
+    );
+
+    if let (Some(stdout), Some(tx)) = (child.stdout.take(), stdout_tx) {
+        pump(stdout, tx, logs.clone());
+    }
+    if let (Some(stderr), Some(tx)) = (child.stderr.take(), stderr_tx) {
+        pump(stderr, tx, logs.clone());
+    }
+
+    Ok(child)
+}
+
+/// 设置进程组、umask、资源限制等需要在 exec 之前完成的属性
+#[cfg(unix)]
+fn configure_process(command: &mut std::process::Command, ctx: &ServerContext) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let body = &ctx.body;
+
+    // 独立的进程组，停止时可以一并结束其派生的进程
+    command.process_group(0);
+
+    let umask = match &body.umask {
+        Some(umask) => Some(parse_umask(umask)?),
+        None => None,
+    };
+
+    let credentials = Credentials::resolve(body)?;
+    if let Some(User { name, home }) = credentials.as_ref().and_then(|c| c.user.as_ref()) {
+        for (key, value) in [("USER", name), ("LOGNAME", name), ("HOME", home)] {
+            if !body.env.set.contains_key(key) {
+                command.env(key, value);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    let cgroup_procs = match &ctx.cgroup {
+        Some(cgroup) => Some(cgroup.procs_file()?),
+        None => None,
+    };
+    let limits = body.limits.clone();
+
+    // 加入 cgroup 和设置 rlimit 都需要在切换用户之前完成
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(umask) = umask {
+                libc::umask(umask);
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(procs) = &cgroup_procs {
+                join_cgroup(procs)?;
+            }
+            apply_rlimits(&limits)?;
+            if let Some(credentials) = &credentials {
+                credentials.apply()?;
+            }
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn configure_process(_command: &mut std::process::Command, ctx: &ServerContext) -> Result<()> {
+    let body = &ctx.body;
+    if body.umask.is_some() {
+        anyhow::bail!("umask is not supported on this platform");
+    }
+    if body.user.is_some() || body.group.is_some() || !body.capabilities.is_empty() {
+        anyhow::bail!("user, group and capabilities are not supported on this platform");
+    }
+    let limits = &body.limits;
+    if limits.open_files.is_some()
+        || limits.memory.is_some()
+        || limits.cpu_time.is_some()
+        || limits.core_dump.is_some()
+    {
+        anyhow::bail!("rlimits are not supported on this platform");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn parse_umask(umask: &str) -> Result<libc::mode_t> {
+    match libc::mode_t::from_str_radix(umask, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => anyhow::bail!("invalid umask `{umask}`"),
+    }
+}
+
+/// 守护任务：等待进程退出，并按重启策略重新拉起
+pub fn supervise(
+    id: u64,
+    ctx: Arc<ServerContext>,
+    child: Child,
+    mut stop_rx: watch::Receiver<bool>,
+    mut signal_rx: mpsc::UnboundedReceiver<Signal>,
+    exit_tx: watch::Sender<Option<ExitStatus>>,
+) {
+    tokio::spawn(async move {
+        let name = ctx.body.name.clone();
+        let policy = ctx.body.restart.clone();
+        let mut retries: u32 = 0;
+        let mut started_at = Instant::now();
+        let mut server = ServerProcess::new(child, ctx.oom_kills());
+
+        loop {
+            let status = server.wait(&mut signal_rx).await;
+            exit_tx.send_replace(status);
+            match status {
+                Some(status) => log::info!("`{name}` exited with {status}"),
+                None => log::warn!("`{name}` exited with unknown status"),
+            }
+
+            if *stop_rx.borrow() {
+                // /stop 超时返回后进程才退出时，由这里移除记录
+                remove_stopped(&name, id);
+                break;
+            }
+
+            if started_at.elapsed() >= Duration::from_secs(policy.reset_after_secs) {
+                retries = 0;
+            }
+
+            let restart = should_restart(&policy, status)
+                && (policy.max_retries == 0 || retries < policy.max_retries);
+
+            let limit_hit = exit_limit_hit(status, ctx.oom_kills() > server.oom_kills);
+            if let Some(limit) = limit_hit {
+                log::warn!("`{name}` hit resource limit {limit:?}");
+            }
+
+            update_status(id, &name, |s| {
+                s.exit_code = status.and_then(|st| st.code());
+                s.signal = status.and_then(exit_signal);
+                s.limit_hit = limit_hit;
+                s.state = match (restart, status) {
+                    (true, _) => ServerState::Starting,
+                    (false, Some(st)) if st.success() => ServerState::Exited,
+                    (false, _) => ServerState::Crashed,
+                };
+            });
+            if !restart {
+                break;
+            }
+
+            // 结束已逃出进程组的残留进程，cgroup 在多次重启之间共享
+            #[cfg(target_os = "linux")]
+            if let Some(cgroup) = &ctx.cgroup {
+                cgroup.kill();
+            }
+
+            // 重启失败同样计入重试次数
+            loop {
+                let delay = backoff_delay(&policy, retries);
+                retries += 1;
+                log::info!("restarting `{name}` in {delay:?} (attempt {retries})");
+
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = stop_rx.changed() => return,
+                }
+
+                match spawn_server(&ctx) {
+                    Ok(child) => {
+                        server = ServerProcess::new(child, ctx.oom_kills());
+                        exit_tx.send_replace(None);
+                        // 丢弃发给上一个进程的信号
+                        while signal_rx.try_recv().is_ok() {}
+                        break;
+                    }
+                    Err(err) if policy.max_retries == 0 || retries < policy.max_retries => {
+                        log::warn!("failed to restart `{name}`: {err:#}");
+                    }
+                    Err(err) => {
+                        log::error!("failed to restart `{name}`, giving up: {err:#}");
+                        update_status(id, &name, |s| s.state = ServerState::Crashed);
+                        return;
+                    }
+                }
+            }
+            started_at = Instant::now();
+
+            let current = update_status(id, &name, |s| {
+                s.pid = server.child.id().unwrap_or_default();
+                s.state = ServerState::Running;
+                s.started_at = SystemTime::now();
+                s.exit_code = None;
+                s.signal = None;
+                s.restarts += 1;
+            });
+
+            // 实例已被替换或移除，结束新拉起的进程
+            if !current {
+                server.signal(Signal::Kill);
+                server.wait(&mut signal_rx).await;
+                break;
+            }
+        }
+    });
+}
+
+/// 由守护任务持有的子进程，信号只通过它发送
+struct ServerProcess {
+    child: Child,
+    /// 启动时 cgroup 中的 OOM kill 次数
+    oom_kills: u64,
+    /// 不受 pid 复用影响的进程句柄
+    #[cfg(target_os = "linux")]
+    pidfd: Option<OwnedFd>,
+}
+
+impl ServerProcess {
+    fn new(child: Child, oom_kills: u64) -> Self {
+        Self {
+            oom_kills,
+            #[cfg(target_os = "linux")]
+            pidfd: child.id().and_then(pidfd_open),
+            child,
+        }
+    }
+
+    /// 等待进程退出并回收，期间转发收到的信号
+    #[cfg(unix)]
+    async fn wait(
+        &mut self,
+        signal_rx: &mut mpsc::UnboundedReceiver<Signal>,
+    ) -> Option<ExitStatus> {
+        let mut exited = wait_exited(self.child.id());
+        loop {
+            tokio::select! {
+                _ = &mut exited => break,
+                Some(signal) = signal_rx.recv() => self.signal(signal),
+            }
+        }
+
+        // 组长回收之前 pgid 不会被复用，此时结束组内残留的进程
+        if let Some(pid) = self.child.id() {
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+        self.child.wait().await.ok()
+    }
+
+    #[cfg(not(unix))]
+    async fn wait(
+        &mut self,
+        signal_rx: &mut mpsc::UnboundedReceiver<Signal>,
+    ) -> Option<ExitStatus> {
+        loop {
+            tokio::select! {
+                status = self.child.wait() => return status.ok(),
+                Some(signal) = signal_rx.recv() => self.signal(signal),
+            }
+        }
+    }
+
+    /// 信号发给整个进程组，组长已离开进程组时另外发给组长
+    fn signal(&mut self, signal: Signal) {
+        // 进程被回收后 id 为 None，不会误伤复用该 pid 的进程
+        let Some(pid) = self.child.id() else {
+            return;
+        };
+
+        #[cfg(unix)]
+        if let Some(signum) = signal_number(signal) {
+            // 组长还未被回收，pgid 不会被复用
+            let pgid = pid as libc::pid_t;
+            unsafe {
+                libc::killpg(pgid, signum);
+            }
+            if unsafe { libc::getpgid(pgid) } != pgid {
+                self.signal_leader(pid, signum);
+            }
+            return;
+        }
+
+        let _ = pid;
+        let _ = self.child.start_kill();
+    }
+
+    #[cfg(unix)]
+    fn signal_leader(&self, pid: u32, signum: i32) {
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &self.pidfd {
+            unsafe {
+                libc::syscall(
+                    libc::SYS_pidfd_send_signal,
+                    pidfd.as_raw_fd(),
+                    signum,
+                    std::ptr::null::<libc::siginfo_t>(),
+                    0,
+                );
+            }
+            return;
+        }
+
+        unsafe {
+            libc::kill(pid as libc::pid_t, signum);
+        }
+    }
+}
+
+/// 进程退出后返回，但不回收，以便之后安全地向进程组发送信号
+#[cfg(unix)]
+fn wait_exited(pid: Option<u32>) -> tokio::task::JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let Some(pid) = pid else {
+            return;
+        };
+        loop {
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            let ret = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                )
+            };
+            if ret == 0 || std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted
+            {
+                return;
+            }
+        }
+    })
+}
+
+/// 内核不支持 pidfd（早于 5.3）时返回 None
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: u32) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return None;
+    }
+    Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// 更新指定实例的状态，实例已被替换或移除时返回 false
+fn update_status(id: u64, name: &str, f: impl FnOnce(&mut ServerStatus)) -> bool {
+    let mut arc = ServerRegistry::global().lock();
+    match arc.servers.get_mut(name) {
+        Some(status) if status.id == id => {
+            f(status);
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+fn exit_limit_hit(status: Option<ExitStatus>, oom_killed: bool) -> Option<LimitHit> {
+    limit_hit(status, oom_killed)
+}
+
+#[cfg(not(unix))]
+fn exit_limit_hit(_status: Option<ExitStatus>, _oom_killed: bool) -> Option<LimitHit> {
+    None
+}
+
+#[cfg(unix)]
+pub fn exit_signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub fn exit_signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+fn should_restart(policy: &RestartPolicy, status: Option<ExitStatus>) -> bool {
+    match policy.mode {
+        RestartMode::Never => false,
+        RestartMode::Always => true,
+        RestartMode::OnFailure => !status.map(|s| s.success()).unwrap_or(false),
+    }
+}
+
+/// 指数退避，并加上随机抖动
+fn backoff_delay(policy: &RestartPolicy, retries: u32) -> Duration {
+    let base = policy
+        .backoff_ms
+        .saturating_mul(1u64 << retries.min(16))
+        .min(policy.max_backoff_ms);
+
+    let jitter = policy.jitter.clamp(0.0, 1.0);
+    let factor = if jitter > 0.0 {
+        1.0 + rand::thread_rng().gen_range(-jitter..=jitter)
+    } else {
+        1.0
+    };
+
+    Duration::from_millis((base as f64 * factor) as u64)
+}
+
+/// 获取所有进程的信息
+pub fn processes() -> System {
+    System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::everything()))
+}
+
+/// 列出进程的所有子孙进程，附带启动时间以便之后确认 pid 未被复用
+pub fn descendants(system: &System, pid: u32) -> Vec<(Pid, u64)> {
+    let mut children: HashMap<Pid, Vec<&Process>> = HashMap::new();
+    for proc in system.processes().values() {
+        if let Some(parent) = proc.parent() {
+            children.entry(parent).or_default().push(proc);
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut queue = vec![Pid::from_u32(pid)];
+    while let Some(pid) = queue.pop() {
+        for proc in children.get(&pid).into_iter().flatten() {
+            result.push((proc.pid(), proc.start_time()));
+            queue.push(proc.pid());
+        }
+    }
+    result.sort();
+    result
+}
+
+/// 向快照中仍然存活、且已离开进程组的子孙进程发送信号
+/// 组内的进程由守护任务在组长回收前处理，按启动时间确认 pid 未被复用
+pub fn signal_escaped(pgid: u32, tree: &[(Pid, u64)], signal: Signal) {
+    if tree.is_empty() {
+        return;
+    }
+    let system = processes();
+    for (pid, start_time) in tree {
+        if let Some(proc) = system.process(*pid) {
+            if proc.start_time() != *start_time || in_group(*pid, pgid) {
+                continue;
+            }
+            if proc.kill_with(signal).is_none() {
+                proc.kill();
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn in_group(pid: Pid, pgid: u32) -> bool {
+    unsafe { libc::getpgid(pid.as_u32() as libc::pid_t) == pgid as libc::pid_t }
+}
+
+#[cfg(not(unix))]
+fn in_group(_pid: Pid, _pgid: u32) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn signal_number(signal: Signal) -> Option<i32> {
+    let signum = match signal {
+        Signal::Hangup => libc::SIGHUP,
+        Signal::Interrupt => libc::SIGINT,
+        Signal::Quit => libc::SIGQUIT,
+        Signal::Kill => libc::SIGKILL,
+        Signal::User1 => libc::SIGUSR1,
+        Signal::User2 => libc::SIGUSR2,
+        Signal::Term => libc::SIGTERM,
+        _ => return None,
+    };
+    Some(signum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(mode: RestartMode) -> RestartPolicy {
+        RestartPolicy {
+            mode,
+            backoff_ms: 100,
+            max_backoff_ms: 1000,
+            jitter: 0.0,
+            ..RestartPolicy::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let policy = policy(RestartMode::Always);
+        let delays: Vec<u128> = (0..6)
+            .map(|retries| backoff_delay(&policy, retries).as_millis())
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(
+            backoff_delay(&policy, u32::MAX),
+            Duration::from_millis(1000)
+        );
+    }
+
+    #[test]
+    fn backoff_jitter_stays_in_range() {
+        let policy = RestartPolicy {
+            jitter: 0.5,
+            ..policy(RestartMode::Always)
+        };
+        for _ in 0..100 {
+            let delay = backoff_delay(&policy, 1).as_millis();
+            assert!((100..=300).contains(&delay), "{delay}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restart_by_mode() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let success = Some(ExitStatus::from_raw(0));
+        let failure = Some(ExitStatus::from_raw(1 << 8));
+        let killed = Some(ExitStatus::from_raw(libc::SIGKILL));
+        let statuses = [success, failure, killed, None];
+
+        let restarts = |mode| statuses.map(|status| should_restart(&policy(mode), status));
+        assert_eq!(restarts(RestartMode::Never), [false; 4]);
+        assert_eq!(restarts(RestartMode::Always), [true; 4]);
+        assert_eq!(restarts(RestartMode::OnFailure), [false, true, true, true]);
+    }
+}
@@ -1,33 +1,126 @@
+use super::config::Config;
 use super::data::*;
-use anyhow::{bail, Context, Ok, Result};
+use super::dns::{self, SavedDns};
+use super::output::LogBuffer;
+use super::supervisor::*;
+use anyhow::{anyhow, bail, Context, Ok, Result};
+use futures_util::{stream, Stream, StreamExt};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
-use std::collections::HashMap;
-use std::fs::File;
-use std::path::Path;
-use std::process::Command;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::process::ExitStatus;
 use std::sync::Arc;
-use sysinfo::{ProcessRefreshKind, RefreshKind, System};
-#[derive(Debug, Default)]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sysinfo::{Signal, System};
+use tokio::sync::{broadcast::error::RecvError, mpsc, watch};
+use warp::sse::Event;
+/// 默认等待进程退出的时间
+const STOP_TIMEOUT_MS: u64 = 5000;
+/// 发送 SIGKILL 后等待回收的时间
+const KILL_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug)]
 pub struct ServerStatus {
-    pub info: Option<StartBody>,
+    pub id: u64,
+    pub ctx: Arc<ServerContext>,
     pub pid: u32,
+    pub state: ServerState,
+    pub started_at: SystemTime,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub restarts: u32,
+    pub limit_hit: Option<LimitHit>,
+    /// 通知守护任务不再重启进程
+    pub stop_tx: watch::Sender<bool>,
+    /// 通过守护任务向进程发送信号
+    pub signal_tx: mpsc::UnboundedSender<Signal>,
+    /// 守护任务回收进程后发布的退出状态
+    pub exit_rx: watch::Receiver<Option<ExitStatus>>,
 }
-#[allow(dead_code)]
+
+impl ServerStatus {
+    pub fn to_info(&self, system: &System) -> ServerInfo {
+        let (uptime, children) = match self.state {
+            ServerState::Running | ServerState::Stopping => (
+                self.started_at.elapsed().unwrap_or_default().as_secs(),
+                descendants(system, self.pid)
+                    .into_iter()
+                    .map(|(pid, _)| pid.as_u32())
+                    .collect(),
+            ),
+            _ => (0, Vec::new()),
+        };
+        let start_time = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        ServerInfo {
+            name: self.ctx.body.name.clone(),
+            pid: self.pid,
+            state: self.state,
+            start_time,
+            uptime,
+            exit_code: self.exit_code,
+            signal: self.signal,
+            restarts: self.restarts,
+            limit_hit: self.limit_hit,
+            children,
+            config: self.ctx.body.clone(),
+        }
+    }
+}
+
+/// 按实例名管理的 server 进程
 #[derive(Debug, Default)]
+pub struct ServerRegistry {
+    pub servers: HashMap<String, ServerStatus>,
+    /// 正在启动的实例名，同名实例的启动不能并发进行
+    starting: HashSet<String>,
+}
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct DNSStatus {
-    pub dns: Option<String>,
+    /// 修改前的设置，还原后清空
+    pub saved: Option<SavedDns>,
+    /// 最近一次设置的 DNS
+    #[serde(default)]
+    pub applied: Option<DnsBody>,
 }
 
-impl ServerStatus {
-    pub fn global() -> &'static Arc<Mutex<ServerStatus>> {
-        static CLASHSTATUS: OnceCell<Arc<Mutex<ServerStatus>>> = OnceCell::new();
+impl ServerRegistry {
+    pub fn global() -> &'static Arc<Mutex<ServerRegistry>> {
+        static SERVERREGISTRY: OnceCell<Arc<Mutex<ServerRegistry>>> = OnceCell::new();
 
-        CLASHSTATUS.get_or_init(|| Arc::new(Mutex::new(ServerStatus::default())))
+        SERVERREGISTRY.get_or_init(|| Arc::new(Mutex::new(ServerRegistry::default())))
+    }
+}
+
+/// 启动期间占用实例名，结束（包括失败）时释放
+struct StartGuard(String);
+
+impl StartGuard {
+    fn acquire(name: &str) -> Result<Self> {
+        if !ServerRegistry::global()
+            .lock()
+            .starting
+            .insert(name.to_string())
+        {
+            bail!("server `{name}` is already starting");
+        }
+        Ok(Self(name.to_string()))
+    }
+}
+
+impl Drop for StartGuard {
+    fn drop(&mut self) {
+        ServerRegistry::global().lock().starting.remove(&self.0);
     }
 }
 
-#[allow(dead_code)]
 impl DNSStatus {
     pub fn global() -> &'static Arc<Mutex<DNSStatus>> {
         static DNSSTAUS: OnceCell<Arc<Mutex<DNSStatus>>> = OnceCell::new();
@@ -49,224 +142,383 @@ pub fn version() -> Result<HashMap<String, String>> {
     Ok(map)
 }
 
+/// POST /reload
+/// 重新读取并校验配置，失败时保留当前配置
+pub fn reload() -> Result<ReloadInfo> {
+    Config::reload()
+}
+
 /// POST /start
 /// 启动进程
-pub fn start(body: StartBody) -> Result<()> {
-    // stop the old server
-    let _ = stop();
-
-    let body_cloned = body.clone();
-
-    let log = File::create(body.log_file).context("failed to open log")?;
-    let result = Command::new(body.bin_path)
-        .args(body.args)
-        .stdout(log)
-        .spawn()?;
+pub async fn start(body: StartBody) -> Result<()> {
+    // 校验失败时保留正在运行的同名 server
+    check_name(&body.name)?;
+    check_spawn(&body)?;
+    // 停止旧实例和插入新实例之间需要等待，期间不能有同名的启动请求
+    let _guard = StartGuard::acquire(&body.name)?;
+    let id = next_id();
+    let ctx = Arc::new(ServerContext::new(id, body)?);
+
+    // stop the old server with the same name
+    let _ = stop(StopQuery::new(&ctx.body.name)).await;
+
+    let child = spawn_server(&ctx)?;
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let (signal_tx, signal_rx) = mpsc::unbounded_channel();
+    let (exit_tx, exit_rx) = watch::channel(None);
+
+    let mut arc = ServerRegistry::global().lock();
+    arc.servers.insert(
+        ctx.body.name.clone(),
+        ServerStatus {
+            id,
+            ctx: ctx.clone(),
+            pid: child.id().unwrap_or_default(),
+            state: ServerState::Running,
+            started_at: SystemTime::now(),
+            exit_code: None,
+            signal: None,
+            restarts: 0,
+            limit_hit: None,
+            stop_tx,
+            signal_tx,
+            exit_rx,
+        },
+    );
+    drop(arc);
 
-    let mut arc = ServerStatus::global().lock();
-    arc.info = Some(body_cloned);
-    arc.pid = result.id();
+    supervise(id, ctx, child, stop_rx, signal_rx, exit_tx);
 
     Ok(())
 }
 
 /// POST /stop
-/// 停止 server 进程
-pub fn stop() -> Result<()> {
-    let mut arc = ServerStatus::global().lock();
+/// 停止 server 进程，先发送 SIGTERM，超时后强制结束
+pub async fn stop(query: StopQuery) -> Result<Option<StopInfo>> {
+    let signal = match &query.signal {
+        Some(signal) => parse_signal(signal)?,
+        None => Signal::Term,
+    };
+    let timeout = Duration::from_millis(query.timeout_ms.unwrap_or(STOP_TIMEOUT_MS));
+
+    // 进程退出前保留记录，停止期间仍可查询，SIGKILL 后仍未退出时也不会丢失
+    let status = {
+        let mut arc = ServerRegistry::global().lock();
+        let Some(status) = arc.servers.get_mut(&query.name) else {
+            // 没有进程在运行
+            return Ok(None);
+        };
+        let _ = status.stop_tx.send(true);
 
-    if arc.info.is_none() {
-        // 没有进程在运行
-        return Ok(());
-    }
+        // 进程已退出，pid 可能已被复用
+        if !matches!(status.state, ServerState::Running | ServerState::Stopping) {
+            arc.servers.remove(&query.name);
+            return Ok(None);
+        }
+        status.state = ServerState::Stopping;
+        StoppingServer {
+            id: status.id,
+            pid: status.pid,
+            ctx: status.ctx.clone(),
+            signal_tx: status.signal_tx.clone(),
+            exit_rx: status.exit_rx.clone(),
+        }
+    };
+
+    let mut exit_rx = status.exit_rx.clone();
+    let mut escalated = false;
 
-    let system = System::new_with_specifics(
-        RefreshKind::new().with_processes(ProcessRefreshKind::everything()),
+    // 进程退出后子进程会被重新挂到 init 下，需要提前记录
+    let tree = descendants(&processes(), status.pid);
+
+    log::info!(
+        "stopping `{}` (pid {}) with {signal:?}",
+        query.name,
+        status.pid
     );
-    let bin_path = arc.info.clone().unwrap().bin_path;
-    let filename = Path::new(&bin_path).file_stem().unwrap().to_str().unwrap();
-    let procs = system.processes_by_name(filename);
-    for proc in procs {
-        if proc.pid().as_u32() == arc.pid {
-            proc.kill();
+    // 进程组由守护任务在回收组长之前发送信号，避免 pgid 被复用
+    let _ = status.signal_tx.send(signal);
+    signal_escaped(status.pid, &tree, signal);
+    let exit = match tokio::time::timeout(timeout, wait_exit(&mut exit_rx)).await {
+        std::result::Result::Ok(exit) => exit,
+        Err(_) => {
+            log::warn!(
+                "`{}` did not exit in {timeout:?}, sending SIGKILL",
+                query.name
+            );
+            escalated = true;
+            let _ = status.signal_tx.send(Signal::Kill);
+            signal_escaped(status.pid, &tree, Signal::Kill);
+            tokio::time::timeout(KILL_TIMEOUT, wait_exit(&mut exit_rx))
+                .await
+                .context("process did not exit after SIGKILL")?
         }
+    };
+
+    // 进程组中的残留进程已由守护任务在回收前结束，这里只清理逃出进程组的子进程
+    signal_escaped(status.pid, &tree, Signal::Kill);
+    #[cfg(target_os = "linux")]
+    if let Some(cgroup) = &status.ctx.cgroup {
+        cgroup.kill();
     }
-    arc.info = None;
-    arc.pid = 0;
-    Ok(())
+    remove_stopped(&query.name, status.id);
+
+    Ok(Some(StopInfo {
+        name: query.name,
+        pid: status.pid,
+        escalated,
+        exit_code: exit.and_then(|s| s.code()),
+        signal: exit.and_then(exit_signal),
+    }))
+}
+
+/// 停止过程中需要的信息，记录本身留在注册表中
+struct StoppingServer {
+    id: u64,
+    pid: u32,
+    ctx: Arc<ServerContext>,
+    signal_tx: mpsc::UnboundedSender<Signal>,
+    exit_rx: watch::Receiver<Option<ExitStatus>>,
+}
+
+/// 进程退出后移除记录，同名实例已被替换时不做任何事
+pub fn remove_stopped(name: &str, id: u64) {
+    let mut arc = ServerRegistry::global().lock();
+    if arc.servers.get(name).is_some_and(|status| status.id == id) {
+        arc.servers.remove(name);
+    }
+}
+
+/// 等待守护任务回收进程，守护任务已结束时返回 None
+async fn wait_exit(exit_rx: &mut watch::Receiver<Option<ExitStatus>>) -> Option<ExitStatus> {
+    match exit_rx.wait_for(|s| s.is_some()).await {
+        std::result::Result::Ok(status) => *status,
+        Err(_) => None,
+    }
+}
+
+fn parse_signal(name: &str) -> Result<Signal> {
+    let name = name.to_ascii_uppercase();
+    let signal = match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => Signal::Hangup,
+        "INT" => Signal::Interrupt,
+        "QUIT" => Signal::Quit,
+        "KILL" => Signal::Kill,
+        "USR1" => Signal::User1,
+        "USR2" => Signal::User2,
+        "TERM" => Signal::Term,
+        _ => bail!("unsupported signal `{name}`"),
+    };
+    Ok(signal)
 }
 
 /// GET /info
 /// 获取 server 当前执行信息
-pub fn info() -> Result<StartBody> {
-    let arc = ServerStatus::global().lock();
+pub fn info(name: &str) -> Result<ServerInfo> {
+    let system = processes();
+    let arc = ServerRegistry::global().lock();
 
-    match arc.info.clone() {
-        Some(info) => Ok(info),
-        None => bail!("server not executed"),
+    match arc.servers.get(name) {
+        Some(status) => Ok(status.to_info(&system)),
+        None => bail!("server `{name}` not executed"),
     }
 }
 
-/// POST /set_dns
-/// 设置DNS
-pub fn set_dns(_body: DnsBody) -> Result<()> {
-    #[cfg(target_os = "macos")]
-    {
-        let service = default_network_service().or_else(|_e| default_network_service_by_ns());
-        if let Err(e) = service {
-            return Err(e);
-        }
-        let service = service.unwrap();
-        let mut arc = DNSStatus::global().lock();
-        if arc.dns.is_none() {
-            let output = networksetup()
-                .arg("-getdnsservers")
-                .arg(&service)
-                .output()?;
-            let mut origin_dns = String::from_utf8(output.stdout)?.trim().replace("\n", " ");
-            if origin_dns
-                .trim()
-                .starts_with("There aren't any DNS Servers set on")
-            {
-                origin_dns = "Empty".to_string();
-            }
-            arc.dns = Some(origin_dns);
-        }
+/// GET /list
+/// 获取所有 server 的执行信息
+pub fn list() -> Result<Vec<ServerInfo>> {
+    let system = processes();
+    let arc = ServerRegistry::global().lock();
 
-        networksetup()
-            .arg("-setdnsservers")
-            .arg(&service)
-            .arg(_body.dns)
-            .output()?;
-    }
+    let mut list: Vec<ServerInfo> = arc.servers.values().map(|s| s.to_info(&system)).collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
 
-    Ok(())
+    Ok(list)
 }
 
-/// POST /unset_dns
-/// 还原DNS
-pub fn unset_dns() -> Result<()> {
-    #[cfg(target_os = "macos")]
-    {
-        let mut arc = DNSStatus::global().lock();
+/// GET /logs
+/// 获取 server 最近的输出
+pub fn logs(query: &LogsQuery) -> Result<Vec<String>> {
+    Ok(log_buffer(&query.name)?.tail(query.lines))
+}
 
-        let origin_dns = match arc.dns.clone() {
-            Some(dns) => dns,
-            None => "".to_string(),
-        };
-        if !origin_dns.is_empty() {
-            let service = default_network_service().or_else(|_e| default_network_service_by_ns());
-            if let Err(e) = service {
-                return Err(e);
+/// GET /logs?follow=true
+/// 回放最近的输出后持续推送新的输出
+pub fn follow_logs(
+    query: &LogsQuery,
+) -> Result<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    let (tail, rx) = log_buffer(&query.name)?.follow(query.lines);
+
+    let live = stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                std::result::Result::Ok(line) => return Some((line, rx)),
+                // 客户端读取太慢，跳过丢失的部分
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
             }
-            let service = service.unwrap();
-            networksetup()
-                .arg("-setdnsservers")
-                .arg(service)
-                .arg(origin_dns)
-                .output()?;
-
-            arc.dns = None;
         }
-    }
+    });
 
-    Ok(())
+    Ok(stream::iter(tail)
+        .chain(live)
+        .map(|line| std::result::Result::Ok(Event::default().data(line))))
 }
-#[cfg(target_os = "macos")]
-fn networksetup() -> Command {
-    Command::new("networksetup")
+
+fn log_buffer(name: &str) -> Result<Arc<LogBuffer>> {
+    let arc = ServerRegistry::global().lock();
+    match arc.servers.get(name) {
+        Some(status) => Ok(status.ctx.logs.clone()),
+        None => bail!("server `{name}` not executed"),
+    }
 }
 
-#[cfg(target_os = "macos")]
-fn default_network_service() -> Result<String> {
-    use std::net::{SocketAddr, UdpSocket};
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.connect("1.1.1.1:80")?;
-    let ip = socket.local_addr()?.ip();
-    let addr = SocketAddr::new(ip, 0);
-
-    let interfaces = interfaces::Interface::get_all()?;
-    let interface = interfaces
-        .into_iter()
-        .find(|i| i.addresses.iter().find(|a| a.addr == Some(addr)).is_some())
-        .map(|i| i.name.to_owned());
-
-    match interface {
-        Some(interface) => {
-            let service = get_server_by_order(interface)?;
-            Ok(service)
-        }
-        None => anyhow::bail!("No network service found"),
+/// 实例名只允许字母、数字以及 `-` `_` `.`
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > 64
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!("invalid server name `{name}`");
     }
+    Ok(())
 }
 
-#[cfg(target_os = "macos")]
-fn default_network_service_by_ns() -> Result<String> {
-    let output = networksetup().arg("-listallnetworkservices").output()?;
-    let stdout = String::from_utf8(output.stdout)?;
-    let mut lines = stdout.split('\n');
-    lines.next(); // ignore the tips
-
-    // get the first service
-    match lines.next() {
-        Some(line) => Ok(line.into()),
-        None => anyhow::bail!("No network service found"),
+/// 在修改系统设置之前校验所有地址
+fn parse_servers(servers: &[String]) -> Result<Vec<IpAddr>> {
+    if servers.is_empty() {
+        bail!("at least one dns server is required");
     }
+    servers
+        .iter()
+        .map(|server| {
+            server
+                .parse::<IpAddr>()
+                .map_err(|err| anyhow!("invalid dns server `{server}`: {err}"))
+        })
+        .collect()
 }
 
-#[cfg(target_os = "macos")]
-fn get_server_by_order(device: String) -> Result<String> {
-    let services = listnetworkserviceorder()?;
-    let service = services
-        .into_iter()
-        .find(|(_, _, d)| d == &device)
-        .map(|(s, _, _)| s);
-    match service {
-        Some(service) => Ok(service),
-        None => anyhow::bail!("No network service found"),
+/// 搜索域按主机名校验，每段不超过 63 个字符，总长不超过 253 个字符
+fn check_domain(domain: &str) -> Result<()> {
+    let name = domain.strip_suffix('.').unwrap_or(domain);
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if name.is_empty() || name.len() > 253 || !name.split('.').all(valid_label) {
+        bail!("invalid search domain `{domain}`");
     }
+    Ok(())
 }
 
-#[cfg(target_os = "macos")]
-fn listnetworkserviceorder() -> Result<Vec<(String, String, String)>> {
-    let output = networksetup().arg("-listnetworkserviceorder").output()?;
-    let stdout = String::from_utf8(output.stdout)?;
+/// GET /dns
+/// 获取当前的 DNS 以及修改前的设置
+/// 会执行外部命令，放到阻塞线程中运行
+pub async fn get_dns() -> Result<DnsInfo> {
+    tokio::task::spawn_blocking(dns::dns_info).await?
+}
 
-    let mut lines = stdout.split('\n');
-    lines.next(); // ignore the tips
+/// POST /set_dns
+/// 设置DNS
+pub fn set_dns(body: DnsBody) -> Result<()> {
+    let servers = parse_servers(&body.servers)?;
+    for domain in &body.search_domains {
+        check_domain(domain)?;
+    }
+    dns::set_dns(&servers, &body.search_domains)
+}
+
+/// POST /unset_dns
+/// 还原DNS
+pub fn unset_dns() -> Result<()> {
+    dns::unset_dns()
+}
 
-    let mut services = Vec::new();
-    let mut p: Option<(String, String, String)> = None;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    for line in lines {
-        if !line.starts_with('(') {
-            continue;
+    #[test]
+    fn server_names() {
+        for name in ["core", "a.b-c_1", &"x".repeat(64)] {
+            assert!(check_name(name).is_ok(), "{name}");
+        }
+        for name in ["", ".hidden", "a/b", "../a", "a b", "中文", &"x".repeat(65)] {
+            assert!(check_name(name).is_err(), "{name}");
         }
+    }
 
-        if p.is_none() {
-            let ri = line.find(')');
-            if ri.is_none() {
-                continue;
-            }
-            let ri = ri.unwrap();
-            let service = line[ri + 1..].trim();
-            p = Some((service.into(), "".into(), "".into()));
-        } else {
-            let line = &line[1..line.len() - 1];
-            let pi = line.find("Port:");
-            let di = line.find(", Device:");
-            if pi.is_none() || di.is_none() {
-                continue;
-            }
-            let pi = pi.unwrap();
-            let di = di.unwrap();
-            let port = line[pi + 5..di].trim();
-            let device = line[di + 9..].trim();
-            let (service, _, _) = p.as_mut().unwrap();
-            *p.as_mut().unwrap() = (service.to_owned(), port.into(), device.into());
-            services.push(p.take().unwrap());
+    #[tokio::test]
+    async fn start_rejects_loader_env() {
+        for key in ["LD_PRELOAD", "LD_LIBRARY_PATH", "DYLD_INSERT_LIBRARIES"] {
+            let body: StartBody = serde_json::from_value(serde_json::json!({
+                "name": "loader-env",
+                "bin_path": "/bin/sh",
+                "args": [],
+                "log_file": "/var/log/desktop-service/loader-env.log",
+                "env": { "set": { key: "/tmp/evil.so" } },
+            }))
+            .unwrap();
+            let err = start(body).await.unwrap_err();
+            let err = err.downcast_ref::<ApiError>().unwrap();
+            assert_eq!(err.code, CODE_FORBIDDEN, "{key}");
+            assert!(err.msg.contains(key));
         }
+        assert!(!ServerRegistry::global()
+            .lock()
+            .servers
+            .contains_key("loader-env"));
+    }
+
+    #[test]
+    fn concurrent_start_rejected() {
+        let guard = StartGuard::acquire("concurrent").unwrap();
+        assert!(StartGuard::acquire("concurrent").is_err());
+        assert!(StartGuard::acquire("other").is_ok());
+        drop(guard);
+        assert!(StartGuard::acquire("concurrent").is_ok());
     }
 
-    Ok(services)
+    #[test]
+    fn dns_servers() {
+        let servers = parse_servers(&["1.1.1.1".into(), "::1".into()]).unwrap();
+        assert_eq!(
+            servers,
+            ["1.1.1.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]
+        );
+        assert!(parse_servers(&[]).is_err());
+        let err = parse_servers(&["1.1.1.1".into(), "1.1.1.1:53".into()]).unwrap_err();
+        assert!(err.to_string().contains("1.1.1.1:53"));
+    }
+
+    #[test]
+    fn search_domains() {
+        let label = "a".repeat(63);
+        let long = [label.as_str(); 4].join(".");
+        for domain in ["lan", "example.com", "example.com.", "a-b.c0", &label] {
+            assert!(check_domain(domain).is_ok(), "{domain}");
+        }
+        for domain in [
+            "",
+            ".",
+            "-a.com",
+            "a-.com",
+            "a..com",
+            "a_b.com",
+            "a b.com",
+            "~.",
+            &"a".repeat(64),
+            &long,
+        ] {
+            assert!(check_domain(domain).is_err(), "{domain}");
+        }
+    }
 }